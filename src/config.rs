@@ -1,15 +1,101 @@
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, hash::Hash, process::exit};
+use itertools::Itertools;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    fmt::Display,
+    fs,
+    hash::Hash,
+    io,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
 
-use crate::{model::AnsiblePlay, subprocess};
+use crate::{
+    error::{InvalidConfigError, InvalidRoleError, UnknownUserError},
+    inventory::Inventory,
+    keys,
+    model::{AnsiblePlay, AnsibleTask},
+    plays::PlayOptions,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Serialize, Hash, Eq, PartialEq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Blocked,
+    Disabled,
     Sudoer,
     Nopass,
     SuperUser,
+    Absent,
+}
+
+impl FromStr for Role {
+    type Err = InvalidRoleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocked" | "locked" => Ok(Self::Blocked),
+            "disabled" => Ok(Self::Disabled),
+            "sudoer" | "sudo" => Ok(Self::Sudoer),
+            "nopass" | "nopasswd" => Ok(Self::Nopass),
+            "superuser" | "root" => Ok(Self::SuperUser),
+            "absent" => Ok(Self::Absent),
+            _ => Err(InvalidRoleError {
+                role: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Accepts the aliases of each role, and lists the valid roles if given an unknown one.
+impl<'de> Deserialize<'de> for Role {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RoleVisitor;
+
+        impl Visitor<'_> for RoleVisitor {
+            type Value = Role;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a role name")
+            }
+
+            // Parsing here, rather than after deserializing a string, keeps the value's location in errors.
+            fn visit_str<E: de::Error>(self, role: &str) -> Result<Self::Value, E> {
+                role.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(RoleVisitor)
+    }
+}
+
+impl JsonSchema for Role {
+    fn schema_name() -> Cow<'static, str> {
+        "Role".into()
+    }
+
+    /// Lists the aliases after the role names, so configs using them are valid too.
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "enum": [
+                "blocked", "disabled", "sudoer", "nopass", "superuser", "absent",
+                "locked", "sudo", "nopasswd", "root",
+            ],
+        })
+    }
 }
 
 impl Role {
@@ -17,10 +103,38 @@ impl Role {
     pub fn group(&self) -> &'static str {
         match self {
             Self::Blocked => "sshman-blocked",
+            Self::Disabled => "sshman-disabled",
             Self::Sudoer => "sshman-sudoer",
             Self::Nopass => "sshman-nopass",
             Self::SuperUser => "root",
+            Self::Absent => "sshman-absent",
+        }
+    }
+
+    /// Returns whether users with this role have their keys authorized.
+    /// Blocked users have their keys removed, and absent users have their account removed.
+    pub fn authorizes_keys(&self) -> bool {
+        !matches!(self, Self::Blocked | Self::Absent)
+    }
+
+    /// Returns the content of the sudoers file for the group of a user with this role.
+    /// Sudoers may use sudo with the root password, nopass users may use sudo without a password.
+    /// Each of the extra defaults is added for the group only.
+    pub fn sudoers(&self, defaults: &[String]) -> String {
+        let group = self.group();
+        let mut content = match self {
+            Self::Sudoer => format!("%{group} ALL=(ALL) ALL\nDefaults:%{group} rootpw\n"),
+            Self::Nopass => {
+                format!("%{group} ALL=(ALL) NOPASSWD: ALL\nDefaults:%{group} !requiretty\n")
+            }
+            other => panic!("Creating sudoers content for role {other}"),
+        };
+
+        for default in defaults {
+            content.push_str(&format!("Defaults:%{group} {default}\n"));
         }
+
+        content
     }
 }
 
@@ -28,60 +142,1370 @@ impl Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Blocked => write!(f, "blocked user"),
+            Self::Disabled => write!(f, "disabled user"),
             Self::Sudoer => write!(f, "sudo user"),
             Self::Nopass => write!(f, "passwordless sudo user"),
             Self::SuperUser => write!(f, "super user"),
+            Self::Absent => write!(f, "absent user"),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+/// Prefixes of the crypt hash formats accepted for account passwords;
+/// SHA-512, yescrypt and bcrypt.
+const PASSWORD_HASH_PREFIXES: [&str; 3] = ["$6$", "$y$", "$2b$"];
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub struct AccessStmt {
     pub hosts: String,
     pub role: Role,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub groups: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub seuser: Option<String>,
+    /// Umask used when creating the home directory of a new account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+    /// Login shell for the account, e.g. `/bin/zsh`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    /// Path to the account's home directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    /// Options to restrict the keys with, e.g. `from="10.0.0.0/8",no-pty`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_options: Option<String>,
+    /// Whether to remove keys that aren't configured from the user's authorized_keys.
+    /// Defaults to true.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclusive: Option<bool>,
+    /// Whether to manage the account in the local /etc/passwd, even if the host uses a directory service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local: Option<bool>,
+    /// Whether to generate an SSH keypair for the account on the host, if it doesn't have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generate_ssh_key: Option<bool>,
+    /// Number of bits in the generated key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_key_bits: Option<u32>,
+    /// Type of the generated key, e.g. `ed25519`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_key_type: Option<String>,
+    /// Date the account expires on, as `YYYY-MM-DD`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// Public keys of CAs trusted to sign certificates for any user on these hosts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_cas: Vec<String>,
+    /// Whether to gather facts before managing the account and keys,
+    /// for values that are templates using facts, e.g. `/home/{{ ansible_hostname }}`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub gather_facts: bool,
+    /// User to become instead of root, for hosts where the admin user must be used to get sudo.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub become_user: Option<String>,
+    /// Whether to escalate privileges when authorizing keys. Defaults to true.
+    /// Can be false when the connecting user is the managed user, so no sudo is needed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#become: Option<bool>,
+    /// Crypt hash of a password to log in with, instead of the locked password accounts get.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// Path of the authorized_keys file, for hosts where sshd doesn't read `~/.ssh/authorized_keys`.
+    /// `%u` is replaced with the user name, as in sshd's AuthorizedKeysFile.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorized_keys_path: Option<String>,
+    /// Whether to delete the home directory when removing the account of an absent user.
+    /// Defaults to false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remove_home: Option<bool>,
+    /// Group to make the primary group of sudoer and nopass accounts, e.g. `staff`,
+    /// instead of a private group named after the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_group: Option<String>,
+    /// Keys to authorize on these hosts in place of the user's `pubkeys`,
+    /// e.g. to only allow one key on a jump host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkeys: Option<Vec<PubKey>>,
+    /// Jinja expression the hosts must satisfy for the account and keys to be managed there,
+    /// e.g. `ansible_os_family == 'Debian'`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+}
+
+impl AccessStmt {
+    /// Returns whether the plays for this statement need facts gathered.
+    /// Facts are slow to gather so they are only gathered when asked for.
+    pub fn needs_facts(&self) -> bool {
+        self.gather_facts
+    }
+
+    /// Returns the groups created for the user's account on these hosts; the extra groups,
+    /// and a private group named after the user unless a primary group is set.
+    pub fn created_groups<'a>(&'a self, user: &'a str) -> Vec<&'a str> {
+        let private = Some(user).filter(|_| self.primary_group.is_none());
+        self.groups
+            .iter()
+            .map(String::as_str)
+            .chain(private)
+            .collect()
+    }
+
+    /// Adds the statement's condition, if it has one, to a task managing its hosts.
+    pub fn condition<'a>(&self, task: AnsibleTask<'a>) -> AnsibleTask<'a> {
+        match &self.when {
+            Some(condition) => task.when(condition),
+            None => task,
+        }
+    }
+
+    /// Returns the custom authorized_keys path for the user, if one is set.
+    pub fn authorized_keys_file(&self, user: &str) -> Option<String> {
+        self.authorized_keys_path
+            .as_ref()
+            .map(|path| path.replace("%u", user))
+    }
+
+    /// Returns the expiry date as a unix timestamp, if it is set and valid.
+    pub fn expiry_timestamp(&self) -> Option<i64> {
+        self.expires.as_deref().and_then(parse_date)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+/// Parses a `YYYY-MM-DD` date into the unix timestamp at the start of that day, in UTC.
+fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let mut next = |len: usize| {
+        parts
+            .next()
+            .filter(|part| part.len() == len && part.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|part| part.parse::<i64>().ok())
+    };
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=month_days).contains(&day) {
+        return None;
+    }
+
+    // Counts days from 1970-01-01, with years starting in March so leap days come last.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    Some((era * 146097 + day_of_era - 719468) * 86400)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    Present,
+    Absent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
+#[serde(from = "PubKeyEntry", into = "PubKeyEntry")]
+/// Models a public key in the config file.
+pub struct PubKey {
+    pub key: String,
+    /// Whether the key should be present or absent. Keys without a state are managed exclusively.
+    pub state: Option<KeyState>,
+    /// Options to restrict this key with, e.g. `from="10.0.0.1"`, in place of any in the key.
+    /// Ansible replaces these with the access statement's `key_options` if it has them.
+    pub options: Option<String>,
+    /// Comment for this key, in place of any in the key.
+    pub comment: Option<String>,
+}
+
+impl PubKey {
+    /// Returns the line for this key in an authorized_keys file, with its options and comment.
+    /// Options are written on the line rather than passed to ansible as `key_options`,
+    /// so keys with different options can still be managed by one exclusive task.
+    pub fn line(&self) -> String {
+        if self.options.is_none() && self.comment.is_none() {
+            return self.key.clone();
+        }
+        let Ok(parsed) = keys::parse_key(&self.key) else {
+            return self.key.clone();
+        };
+
+        let options = self.options.as_deref().or(parsed.options);
+        let comment = self.comment.as_deref().or(parsed.comment);
+        options
+            .into_iter()
+            .chain([parsed.key_type, parsed.body])
+            .chain(comment)
+            .join(" ")
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+/// A public key as written in the config file; either just the key or a mapping.
+enum PubKeyEntry {
+    Plain(String),
+    Detailed {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<KeyState>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        options: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+    },
+}
+
+impl From<PubKeyEntry> for PubKey {
+    fn from(entry: PubKeyEntry) -> Self {
+        match entry {
+            PubKeyEntry::Plain(key) => Self {
+                key,
+                state: None,
+                options: None,
+                comment: None,
+            },
+            PubKeyEntry::Detailed {
+                key,
+                state,
+                options,
+                comment,
+            } => Self {
+                key,
+                state,
+                options,
+                comment,
+            },
+        }
+    }
+}
+
+impl From<PubKey> for PubKeyEntry {
+    fn from(pubkey: PubKey) -> Self {
+        match pubkey {
+            PubKey {
+                key,
+                state: None,
+                options: None,
+                comment: None,
+            } => Self::Plain(key),
+            PubKey {
+                key,
+                state,
+                options,
+                comment,
+            } => Self::Detailed {
+                key,
+                state,
+                options,
+                comment,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 /// Models a user in the config file.
 pub struct SSHUser {
     pub name: String,
-    pub pubkeys: Vec<String>,
+    /// Written to the GECOS field of the user's accounts, e.g. an owner and ticket number.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// UID for the user's accounts, so it is the same on every host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub pubkeys: Vec<PubKey>,
+    /// Files of public keys, relative to the config file, to add to `pubkeys` when it is read.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pubkey_files: Vec<String>,
     pub access: Vec<AccessStmt>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(transparent)]
+impl SSHUser {
+    /// Returns the keys that should be authorized for this user on any host,
+    /// sorted and without duplicates.
+    pub fn present_keys(&self) -> impl Iterator<Item = &str> {
+        self.keys()
+            .filter(|pubkey| pubkey.state != Some(KeyState::Absent))
+            .map(|pubkey| pubkey.key.as_str())
+    }
+
+    /// Returns the content of the user's authorized_keys file on the statement's hosts,
+    /// as it is given to ansible.
+    pub fn authorized_keys(&self, stmt: &AccessStmt) -> String {
+        self.stmt_keys(stmt)
+            .filter(|pubkey| pubkey.state != Some(KeyState::Absent))
+            .map(PubKey::line)
+            .join("\n")
+    }
+
+    /// Returns the keys that are explicitly marked for removal on the statement's hosts,
+    /// sorted and without duplicates.
+    pub fn absent_keys<'a>(&'a self, stmt: &'a AccessStmt) -> impl Iterator<Item = &'a str> {
+        self.stmt_keys(stmt)
+            .filter(|pubkey| pubkey.state == Some(KeyState::Absent))
+            .map(|pubkey| pubkey.key.as_str())
+    }
+
+    /// Returns all of the keys for this user, including those of their access statements,
+    /// sorted and without duplicates.
+    /// A key listed more than once keeps the state from its first entry.
+    pub fn keys(&self) -> impl Iterator<Item = &PubKey> {
+        self.pubkeys
+            .iter()
+            .chain(
+                self.access
+                    .iter()
+                    .flat_map(|stmt| stmt.pubkeys.iter().flatten()),
+            )
+            .unique_by(|pubkey| &pubkey.key)
+            .sorted_by(|a, b| a.key.cmp(&b.key))
+    }
+
+    /// Returns the keys for the statement's hosts; its own keys if it has them,
+    /// or else the user's keys. Sorted and without duplicates.
+    pub fn stmt_keys<'a>(&'a self, stmt: &'a AccessStmt) -> impl Iterator<Item = &'a PubKey> {
+        stmt.pubkeys
+            .as_ref()
+            .unwrap_or(&self.pubkeys)
+            .iter()
+            .unique_by(|pubkey| &pubkey.key)
+            .sorted_by(|a, b| a.key.cmp(&b.key))
+    }
+}
+
+/// Tallies of what a run of the config manages.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    /// Users in the config, including blocked users.
+    pub users: usize,
+    /// Distinct host patterns in the access statements.
+    pub host_patterns: usize,
+    /// Keys authorized for users with access that isn't blocked, counted once per user.
+    pub keys: usize,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Processed {} users on {} host patterns, authorizing {} keys.",
+            self.users, self.host_patterns, self.keys
+        )
+    }
+}
+
+/// The users that differ between a config and an earlier snapshot of it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangeSet {
+    /// Names of the users that are only in the current config.
+    pub added: BTreeSet<String>,
+    /// Names of the users whose keys, access or other settings changed.
+    pub changed: BTreeSet<String>,
+    /// Users that are only in the earlier config, as they were defined there.
+    pub removed: Vec<SSHUser>,
+}
+
+impl ChangeSet {
+    /// Returns plays removing the keys of the removed users, on the hosts they had access to.
+    /// Their accounts are kept, as in a rollback without purging users.
+    pub fn revoke_removed(&self, opts: &PlayOptions) -> Vec<AnsiblePlay<'_>> {
+        let mut plays = self
+            .removed
+            .iter()
+            .flat_map(|user| AnsiblePlay::remove_access(user, false))
+            .collect::<Vec<_>>();
+        opts.apply(&mut plays);
+        plays
+    }
+}
+
+/// The keys in a user's authorized_keys file on a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundKeys {
+    pub host: String,
+    pub user: String,
+    pub keys: Vec<String>,
+}
+
+/// A host key that managed hosts should trust, in their system-wide known_hosts file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KnownHost {
+    /// Host name or address the key is for, as ssh clients will connect to it.
+    pub name: String,
+    /// Public host key, e.g. from `/etc/ssh/ssh_host_ed25519_key.pub`.
+    pub key: String,
+    /// Host pattern of the hosts that should trust the key. Defaults to all hosts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hosts: Option<String>,
+}
+
+impl KnownHost {
+    /// Returns the line for the key in a known_hosts file.
+    pub fn line(&self) -> String {
+        format!("{} {}", self.name, self.key)
+    }
+}
+
+/// The latest version of the config schema.
+pub const CONFIG_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
 /// Models a config file.
+/// The file is either a list of users, or a mapping with the users and other settings.
 pub struct SSHConfig {
+    /// Version of the schema the config was written for.
+    /// A plain list of users is version 1.
+    pub version: u32,
     /// The users defined in the config file.
     pub users: Vec<SSHUser>,
+    /// Extra sudoers `Defaults` for the sudoer and nopass groups, e.g. `timestamp_timeout=5`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sudoers_defaults: Vec<String>,
+    /// How ansible should connect to hosts, if not as configured in the inventory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<Connection>,
+    /// Default number of hosts ansible-playbook manages in parallel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forks: Option<NonZeroU32>,
+    /// Named bundles of public keys, which users can list in `pubkeys` as `@name`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub key_groups: BTreeMap<String, Vec<String>>,
+    /// Host keys to add to the system-wide known_hosts file of managed hosts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub known_hosts: Vec<KnownHost>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+/// Settings for connecting to hosts, passed to ansible-playbook as extra vars.
+/// Extra vars take precedence over the inventory, so these apply to every host.
+pub struct Connection {
+    /// Remote user to connect as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Port sshd listens on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Private key to authenticate with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key_file: Option<String>,
+}
+
+impl Connection {
+    /// Returns these settings with any unset fields taken from the other settings.
+    pub fn or(self, other: Connection) -> Connection {
+        Connection {
+            user: self.user.or(other.user),
+            port: self.port.or(other.port),
+            private_key_file: self.private_key_file.or(other.private_key_file),
+        }
+    }
+
+    /// Returns the ansible variables for the settings that are set.
+    pub fn extra_vars(&self) -> BTreeMap<&'static str, serde_json::Value> {
+        let mut vars = BTreeMap::new();
+        if let Some(user) = &self.user {
+            vars.insert("ansible_user", user.clone().into());
+        }
+        if let Some(port) = self.port {
+            vars.insert("ansible_port", port.into());
+        }
+        if let Some(key) = &self.private_key_file {
+            vars.insert("ansible_ssh_private_key_file", key.clone().into());
+        }
+        vars
+    }
+}
+
+impl Default for SSHConfig {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            users: vec![],
+            sudoers_defaults: vec![],
+            connection: None,
+            forks: None,
+            key_groups: BTreeMap::new(),
+            known_hosts: vec![],
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SSHConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// Accepts a plain list of users as well as the full mapping.
+        struct ConfigVisitor;
+
+        impl<'de> Visitor<'de> for ConfigVisitor {
+            type Value = SSHConfig;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a list of users or a mapping with a users key")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Ok(SSHConfig {
+                    version: 1,
+                    users: Deserialize::deserialize(SeqAccessDeserializer::new(seq))?,
+                    ..Default::default()
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let ConfigMap {
+                    version,
+                    users,
+                    sudoers_defaults,
+                    connection,
+                    forks,
+                    key_groups,
+                    known_hosts,
+                } = Deserialize::deserialize(MapAccessDeserializer::new(map))?;
+                if version == 0 || version > CONFIG_VERSION {
+                    return Err(de::Error::custom(format!(
+                        "unsupported config version {version}, the latest is {CONFIG_VERSION}"
+                    )));
+                }
+                Ok(SSHConfig {
+                    version,
+                    users,
+                    sudoers_defaults,
+                    connection,
+                    forks,
+                    key_groups,
+                    known_hosts,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ConfigVisitor)
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+/// A config file written as a mapping.
+struct ConfigMap {
+    /// Version of the schema the config was written for. Defaults to the latest version.
+    #[serde(default = "latest_version")]
+    version: u32,
+    users: Vec<SSHUser>,
+    /// Extra sudoers `Defaults` for the sudoer and nopass groups, e.g. `timestamp_timeout=5`.
+    #[serde(default)]
+    sudoers_defaults: Vec<String>,
+    /// How ansible should connect to hosts, if not as configured in the inventory.
+    #[serde(default)]
+    connection: Option<Connection>,
+    /// Default number of hosts ansible-playbook manages in parallel.
+    #[serde(default)]
+    forks: Option<NonZeroU32>,
+    /// Named bundles of public keys, which users can list in `pubkeys` as `@name`.
+    #[serde(default)]
+    key_groups: BTreeMap<String, Vec<String>>,
+    /// Host keys to add to the system-wide known_hosts file of managed hosts.
+    #[serde(default)]
+    known_hosts: Vec<KnownHost>,
+}
+
+impl JsonSchema for SSHConfig {
+    fn schema_name() -> Cow<'static, str> {
+        "SSHConfig".into()
+    }
+
+    /// Describes the config as it is written, rather than as it is serialized.
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "A list of users, or a mapping with the users and other settings.",
+            "anyOf": [
+                generator.subschema_for::<Vec<SSHUser>>(),
+                generator.subschema_for::<ConfigMap>(),
+            ],
+        })
+    }
+}
+
+fn latest_version() -> u32 {
+    CONFIG_VERSION
+}
+
+/// How serious a problem found by linting is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Probably a mistake, but the playbook will do what the config says.
+    Warning,
+    /// The playbook won't do what was intended.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found by linting the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Extra information used when checking a config.
+#[derive(Debug, Default)]
+pub struct CheckOptions {
+    /// Fingerprints of keys that must not be authorized.
+    pub revoked: HashSet<String>,
+    /// Inventory to resolve host patterns with. Patterns are not checked without one.
+    pub inventory: Option<Inventory>,
+    /// Allow host patterns that match no hosts, e.g. for groups that are intentionally empty.
+    /// Patterns that can't be resolved are still a problem.
+    pub allow_empty_patterns: bool,
+    /// Allow more than one user to be a superuser on the same host,
+    /// which gives the host more than one uid 0 account.
+    pub allow_multiple_superusers: bool,
 }
 
 impl SSHConfig {
-    /// Creates a playbook to create accounts.
-    pub fn create_accounts(&self) -> Vec<AnsiblePlay> {
+    /// Reads each user's pubkey files, relative to the base directory, and adds their keys.
+    /// Blank lines and comments in the files are ignored.
+    pub fn read_pubkey_files(&mut self, base: &Path) -> Result<(), InvalidConfigError> {
+        for user in &mut self.users {
+            for file in &user.pubkey_files {
+                let path = base.join(file);
+                let content = fs::read_to_string(&path).map_err(|err| InvalidConfigError {
+                    message: format!(
+                        "failed to read pubkey file {} for user {}: {err}",
+                        path.display(),
+                        user.name
+                    ),
+                })?;
+
+                user.pubkeys.extend(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(|key| PubKey {
+                            key: key.to_string(),
+                            state: None,
+                            options: None,
+                            comment: None,
+                        }),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces each `@name` in the pubkeys of the users and their access statements
+    /// with the keys in that key group.
+    /// Any state, options or comment given with the reference apply to each of the keys.
+    pub fn expand_key_groups(&mut self) -> Result<(), InvalidConfigError> {
+        let expand = |user: &str, pubkeys: &mut Vec<PubKey>| {
+            let mut expanded = Vec::with_capacity(pubkeys.len());
+            for pubkey in pubkeys.drain(..) {
+                let Some(name) = pubkey.key.strip_prefix('@') else {
+                    expanded.push(pubkey);
+                    continue;
+                };
+                let Some(keys) = self.key_groups.get(name) else {
+                    return Err(InvalidConfigError {
+                        message: format!("user {user} references undefined key group {name}"),
+                    });
+                };
+                expanded.extend(keys.iter().map(|key| PubKey {
+                    key: key.clone(),
+                    ..pubkey.clone()
+                }));
+            }
+            *pubkeys = expanded;
+            Ok(())
+        };
+
+        for user in &mut self.users {
+            expand(&user.name, &mut user.pubkeys)?;
+            for stmt in &mut user.access {
+                if let Some(pubkeys) = &mut stmt.pubkeys {
+                    expand(&user.name, pubkeys)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces `${VAR}` and `${VAR:-default}` in the string fields of each user and their access
+    /// statements with the value of the variable returned by `lookup`.
+    /// Public keys and names are left as they are. `$${` is replaced with a literal `${`.
+    pub fn expand_vars(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), InvalidConfigError> {
+        for user in &mut self.users {
+            let expand = |value: &mut String| {
+                *value = expand_vars(value, &lookup).map_err(|err| InvalidConfigError {
+                    message: format!("user {} {err}", user.name),
+                })?;
+                Ok::<_, InvalidConfigError>(())
+            };
+
+            for value in user.comment.iter_mut().chain(&mut user.pubkey_files) {
+                expand(value)?;
+            }
+            for stmt in &mut user.access {
+                for value in [&mut stmt.hosts]
+                    .into_iter()
+                    .chain(&mut stmt.groups)
+                    .chain(&mut stmt.trusted_cas)
+                    .chain(
+                        [
+                            &mut stmt.seuser,
+                            &mut stmt.umask,
+                            &mut stmt.shell,
+                            &mut stmt.home,
+                            &mut stmt.key_options,
+                            &mut stmt.ssh_key_type,
+                            &mut stmt.expires,
+                            &mut stmt.become_user,
+                            &mut stmt.password_hash,
+                            &mut stmt.authorized_keys_path,
+                            &mut stmt.primary_group,
+                            &mut stmt.when,
+                        ]
+                        .into_iter()
+                        .flatten(),
+                    )
+                {
+                    expand(value)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merges the users and settings of another config into this one.
+    /// Users can't be split across configs, so a user defined in both is an error.
+    pub fn merge(mut self, other: SSHConfig) -> Result<SSHConfig, InvalidConfigError> {
+        let duplicates = other
+            .users
+            .iter()
+            .filter(|user| self.users.iter().any(|existing| existing.name == user.name))
+            .map(|user| user.name.as_str())
+            .collect::<Vec<_>>();
+        if !duplicates.is_empty() {
+            return Err(InvalidConfigError {
+                message: format!(
+                    "users defined in more than one config file: {}",
+                    duplicates.join(", ")
+                ),
+            });
+        }
+
+        match (&self.connection, other.connection) {
+            (Some(existing), Some(connection)) if *existing != connection => {
+                return Err(InvalidConfigError {
+                    message: "different connection settings in more than one config file"
+                        .to_string(),
+                });
+            }
+            (_, Some(connection)) => self.connection = Some(connection),
+            (_, None) => {}
+        }
+        match (self.forks, other.forks) {
+            (Some(existing), Some(forks)) if existing != forks => {
+                return Err(InvalidConfigError {
+                    message: "different forks in more than one config file".to_string(),
+                });
+            }
+            (_, Some(forks)) => self.forks = Some(forks),
+            (_, None) => {}
+        }
+        for (name, keys) in other.key_groups {
+            match self.key_groups.get(&name) {
+                Some(existing) if *existing != keys => {
+                    return Err(InvalidConfigError {
+                        message: format!(
+                            "different keys for key group {name} in more than one config file"
+                        ),
+                    });
+                }
+                _ => {
+                    self.key_groups.insert(name, keys);
+                }
+            }
+        }
+
+        self.users.extend(other.users);
+        for default in other.sudoers_defaults {
+            if !self.sudoers_defaults.contains(&default) {
+                self.sudoers_defaults.push(default);
+            }
+        }
+        for known_host in other.known_hosts {
+            if !self.known_hosts.contains(&known_host) {
+                self.known_hosts.push(known_host);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Checks the config for problems that would otherwise only show up on the hosts.
+    pub fn check(&self, opts: &CheckOptions) -> Result<(), InvalidConfigError> {
+        let mut problems = vec![];
+
+        let uid_users = self
+            .users
+            .iter()
+            .filter_map(|user| user.uid.map(|uid| (uid, user.name.as_str())))
+            .into_group_map();
+        for (uid, names) in uid_users.into_iter().sorted() {
+            if names.len() > 1 {
+                problems.push(format!("users {} share uid {uid}", names.join(", ")));
+            }
+        }
+
+        for user in &self.users {
+            for stmt in &user.access {
+                if let Some(inventory) = &opts.inventory {
+                    match inventory.get_pattern_hosts(&stmt.hosts) {
+                        Ok(hosts) if hosts.is_empty() && !opts.allow_empty_patterns => problems
+                            .push(format!(
+                                "user {} has host pattern {} which matches no hosts",
+                                user.name, stmt.hosts
+                            )),
+                        Ok(_) => {}
+                        Err(err) => problems.push(format!(
+                            "user {} has host pattern {} which can't be resolved; {err}",
+                            user.name, stmt.hosts
+                        )),
+                    }
+                }
+
+                if stmt.local.is_some() && stmt.role == Role::Blocked {
+                    problems.push(format!(
+                        "user {} sets local on a blocked access statement, which manages no account",
+                        user.name
+                    ));
+                }
+
+                if let Some(group) = &stmt.primary_group {
+                    if group.starts_with("sshman-") {
+                        problems.push(format!(
+                            "user {} has primary_group {group}, which is a group sshman manages for roles",
+                            user.name
+                        ));
+                    }
+                }
+
+                if stmt.r#become == Some(false) && stmt.become_user.is_some() {
+                    problems.push(format!(
+                        "user {} sets become_user on an access statement with become false",
+                        user.name
+                    ));
+                }
+
+                if let Some(expires) = &stmt.expires {
+                    if parse_date(expires).is_none() {
+                        problems.push(format!(
+                            "user {} has invalid expiry date {expires}; expected a date like 2025-06-30",
+                            user.name
+                        ));
+                    }
+                }
+
+                // The hash itself is left out so it isn't printed.
+                if let Some(hash) = &stmt.password_hash {
+                    if !PASSWORD_HASH_PREFIXES
+                        .iter()
+                        .any(|prefix| hash.len() > prefix.len() && hash.starts_with(prefix))
+                    {
+                        problems.push(format!(
+                            "user {} has an invalid password_hash; expected a crypt hash starting with {}",
+                            user.name,
+                            PASSWORD_HASH_PREFIXES.join(", ")
+                        ));
+                    }
+                }
+
+                if let Some(umask) = &stmt.umask {
+                    if !(3..=4).contains(&umask.len())
+                        || !umask.chars().all(|c| ('0'..='7').contains(&c))
+                    {
+                        problems.push(format!(
+                            "user {} has invalid umask {umask}; expected an octal mask like 0077",
+                            user.name
+                        ));
+                    }
+                }
+            }
+
+            for key in user.present_keys() {
+                if let Some(fingerprint) = keys::fingerprint(key) {
+                    if opts.revoked.contains(&fingerprint) {
+                        problems.push(format!(
+                            "user {} has revoked key {fingerprint}: {key}",
+                            user.name
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !opts.allow_multiple_superusers {
+            let mut host_superusers: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+            for user in &self.users {
+                for stmt in user
+                    .access
+                    .iter()
+                    .filter(|stmt| stmt.role == Role::SuperUser)
+                {
+                    // Without an inventory only statements with the same pattern can be compared.
+                    // Patterns that can't be resolved are reported above.
+                    let hosts = match &opts.inventory {
+                        Some(inventory) => inventory
+                            .get_pattern_hosts(&stmt.hosts)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .collect(),
+                        None => vec![format!("host pattern {}", stmt.hosts)],
+                    };
+                    for host in hosts {
+                        host_superusers.entry(host).or_default().insert(&user.name);
+                    }
+                }
+            }
+
+            let shared = host_superusers
+                .into_iter()
+                .filter(|(_, users)| users.len() > 1)
+                .into_group_map_by(|(_, users)| users.clone());
+            for (users, hosts) in shared.into_iter().sorted() {
+                problems.push(format!(
+                    "users {} are all superusers on {}, which would have more than one uid 0 account",
+                    users.iter().join(", "),
+                    hosts.into_iter().map(|(host, _)| host).join(", ")
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidConfigError {
+                message: problems.join("; "),
+            })
+        }
+    }
+
+    /// Returns descriptions of settings that are valid but will have no effect.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        for user in &self.users {
+            for stmt in &user.access {
+                if stmt.expires.is_some() && stmt.role == Role::Blocked {
+                    warnings.push(format!(
+                        "user {} sets expires on blocked host pattern {}, which has no account to expire",
+                        user.name, stmt.hosts
+                    ));
+                }
+
+                if stmt.remove_home.is_some() && stmt.role != Role::Absent {
+                    warnings.push(format!(
+                        "user {} sets remove_home on host pattern {} where they aren't absent, so it is ignored",
+                        user.name, stmt.hosts
+                    ));
+                }
+
+                if stmt.primary_group.is_some() && !matches!(stmt.role, Role::Sudoer | Role::Nopass)
+                {
+                    warnings.push(format!(
+                        "user {} sets primary_group on host pattern {} where they aren't a sudoer or nopass, so it is ignored",
+                        user.name, stmt.hosts
+                    ));
+                }
+
+                if (stmt.ssh_key_bits.is_some() || stmt.ssh_key_type.is_some())
+                    && stmt.generate_ssh_key != Some(true)
+                {
+                    warnings.push(format!(
+                        "user {} sets ssh_key_bits or ssh_key_type on host pattern {} without generate_ssh_key",
+                        user.name, stmt.hosts
+                    ));
+                }
+            }
+        }
+
+        warnings
+    }
+
+    /// Finds logic errors in the config that are valid YAML but probably not what was meant.
+    /// Host patterns that match no hosts are found by `check` when an inventory is given.
+    /// With an inventory, statements with different roles are compared by the hosts they match,
+    /// rather than by their patterns.
+    pub fn lint(&self, inventory: Option<&Inventory>) -> Vec<Finding> {
+        let mut findings = vec![];
+        let error = |message| Finding {
+            severity: Severity::Error,
+            message,
+        };
+        let warning = |message| Finding {
+            severity: Severity::Warning,
+            message,
+        };
+
+        let name_counts = self.users.iter().counts_by(|user| user.name.as_str());
+        for (name, count) in name_counts.into_iter().sorted() {
+            if count > 1 {
+                findings.push(error(format!("user {name} is defined {count} times")));
+            }
+        }
+
+        for user in &self.users {
+            if user
+                .access
+                .iter()
+                .any(|s| s.role.authorizes_keys() && user.stmt_keys(s).next().is_none())
+            {
+                findings.push(warning(format!(
+                    "user {} has access but no public keys to log in with",
+                    user.name
+                )));
+            }
+
+            for (first, second) in user.access.iter().tuple_combinations() {
+                if first.role == second.role {
+                    continue;
+                }
+
+                let resolve = |stmt: &AccessStmt| {
+                    inventory.and_then(|inventory| inventory.get_pattern_hosts(&stmt.hosts).ok())
+                };
+                if let (Some(first_hosts), Some(second_hosts)) = (resolve(first), resolve(second)) {
+                    let overlap = first_hosts.intersection(&second_hosts).sorted().join(", ");
+                    if !overlap.is_empty() {
+                        findings.push(error(format!(
+                            "user {} is a {} on host pattern {} and a {} on host pattern {}, which overlap on {overlap}",
+                            user.name, first.role, first.hosts, second.role, second.hosts
+                        )));
+                    }
+                } else if first.hosts == second.hosts {
+                    findings.push(error(format!(
+                        "user {} is both a {} and a {} on host pattern {}",
+                        user.name, first.role, second.role, first.hosts
+                    )));
+                }
+            }
+        }
+
+        findings.extend(self.warnings().into_iter().map(warning));
+        findings
+    }
+
+    /// Checks that each public key has a recognized type followed by valid key data.
+    /// Keys may have options before them or a comment after them.
+    /// An empty key list is valid; the user's keys will all be removed.
+    pub fn validate_keys(&self) -> Result<(), InvalidConfigError> {
+        let problems = self
+            .users
+            .iter()
+            .flat_map(|user| {
+                let stmt_pubkeys = user
+                    .access
+                    .iter()
+                    .flat_map(|stmt| stmt.pubkeys.iter().flatten());
+                let pubkeys = user
+                    .pubkeys
+                    .iter()
+                    .chain(stmt_pubkeys)
+                    .filter_map(|pubkey| {
+                        // The line also checks the options and comment are valid with the key.
+                        let line = pubkey.line();
+                        keys::parse_key(&pubkey.key)
+                            .and_then(|_| keys::parse_key(&line))
+                            .err()
+                            .map(|err| {
+                                format!("user {} has invalid key ({err}): {line}", user.name)
+                            })
+                    });
+                let cas = user.access.iter().flat_map(|stmt| &stmt.trusted_cas);
+                pubkeys.chain(cas.filter_map(|ca| {
+                    keys::parse_key(ca).err().map(|err| {
+                        format!("user {} has invalid trusted CA ({err}): {ca}", user.name)
+                    })
+                }))
+            })
+            .chain(self.known_hosts.iter().filter_map(|known_host| {
+                keys::parse_key(&known_host.key).err().map(|err| {
+                    format!(
+                        "known host {} has invalid key ({err}): {}",
+                        known_host.name, known_host.key
+                    )
+                })
+            }))
+            .collect::<Vec<_>>();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidConfigError {
+                message: problems.join("; "),
+            })
+        }
+    }
+
+    /// Creates a playbook to create the role groups, their sudoers files, and any additional groups.
+    pub fn bootstrap(&self, opts: &PlayOptions) -> Vec<AnsiblePlay<'_>> {
         let mut plays = vec![AnsiblePlay::create_groups(
             self.users
                 .iter()
                 .flat_map(|usr| &usr.access)
                 .flat_map(|access| access.groups.clone()),
+            &self.sudoers_defaults,
+            opts,
         )];
 
-        plays.extend(self.users.iter().flat_map(AnsiblePlay::create_user));
+        opts.apply(&mut plays);
+        plays
+    }
 
-        plays.extend(self.users.iter().flat_map(AnsiblePlay::authorize_keys));
+    /// Returns the group and content of each sudoers file the bootstrap play creates.
+    pub fn sudoers_files(&self) -> Vec<(&'static str, String)> {
+        [Role::Sudoer, Role::Nopass]
+            .iter()
+            .map(|role| (role.group(), role.sudoers(&self.sudoers_defaults)))
+            .collect()
+    }
 
+    /// Creates a playbook to create accounts.
+    pub fn create_accounts(&self, opts: &PlayOptions) -> Vec<AnsiblePlay<'_>> {
+        let mut account_plays = vec![];
+
+        account_plays.extend(self.users.iter().flat_map(AnsiblePlay::create_user));
+
+        account_plays.extend(self.users.iter().flat_map(AnsiblePlay::authorize_keys));
+
+        account_plays.extend(self.users.iter().flat_map(AnsiblePlay::trust_cas));
+
+        account_plays.extend(AnsiblePlay::manage_known_hosts(self));
+
+        if opts.prune_groups {
+            account_plays.extend(AnsiblePlay::prune_groups(self));
+        }
+
+        // The bootstrap plays already have the options applied.
+        opts.apply(&mut account_plays);
+        // Pruning reads the desired group facts set by the plays before it, so it is kept last
+        // rather than coalesced into an earlier play for the same hosts.
+        let prune = opts.prune_groups.then(|| account_plays.pop()).flatten();
+        let mut plays = self.bootstrap(opts);
+        plays.extend(account_plays);
+
+        let mut plays = if opts.coalesce {
+            AnsiblePlay::coalesce(plays)
+        } else {
+            plays
+        };
+        plays.extend(prune);
         plays
     }
 
-    pub fn display(&self) {
+    /// Creates a playbook that undoes `create_accounts`.
+    /// Keys, trusted CAs, the role groups and their sudoers files are removed,
+    /// and accounts are only deleted with `purge_users`.
+    pub fn rollback_plays(&self, purge_users: bool, opts: &PlayOptions) -> Vec<AnsiblePlay<'_>> {
+        let mut plays = vec![];
+
+        plays.extend(
+            self.users
+                .iter()
+                .flat_map(|user| AnsiblePlay::remove_access(user, purge_users)),
+        );
+
+        plays.extend(self.users.iter().flat_map(AnsiblePlay::untrust_cas));
+
+        plays.push(AnsiblePlay::remove_groups());
+
+        opts.apply(&mut plays);
+        plays
+    }
+
+    /// Resolves each distinct host pattern in the config to the hosts it matches.
+    pub fn resolve_patterns(
+        &self,
+        inventory: &Inventory,
+    ) -> anyhow::Result<HashMap<&str, HashMap<String, String>>> {
         let mut pattern_hosts = HashMap::new();
 
+        for stmt in self.users.iter().flat_map(|user| &user.access) {
+            if !pattern_hosts.contains_key(stmt.hosts.as_str()) {
+                pattern_hosts.insert(stmt.hosts.as_str(), inventory.list_hosts(&stmt.hosts)?);
+            }
+        }
+
+        Ok(pattern_hosts)
+    }
+
+    /// Returns the hosts in the inventory that no access statement's pattern matches,
+    /// e.g. to find hosts that were never given any users.
+    /// Patterns that can't be resolved match no hosts; [`SSHConfig::check`] reports them.
+    pub fn uncovered_hosts(&self, inventory: &Inventory) -> HashSet<String> {
+        let mut hosts = inventory.all_hosts();
+        for pattern in self
+            .users
+            .iter()
+            .flat_map(|user| &user.access)
+            .map(|stmt| &stmt.hosts)
+            .unique()
+        {
+            for host in inventory.get_pattern_hosts(pattern).unwrap_or_default() {
+                hosts.remove(&host);
+            }
+        }
+        hosts
+    }
+
+    /// Returns each user with access to the host and their role there.
+    pub fn host_access(
+        &self,
+        host: &str,
+        pattern_hosts: &HashMap<&str, HashMap<String, String>>,
+    ) -> BTreeSet<(&str, &Role)> {
+        self.users
+            .iter()
+            .flat_map(|user| user.access.iter().map(move |stmt| (user, stmt)))
+            .filter(|(_, stmt)| {
+                pattern_hosts
+                    .get(stmt.hosts.as_str())
+                    .is_some_and(|hosts| hosts.contains_key(host))
+            })
+            .map(|(user, stmt)| (user.name.as_str(), &stmt.role))
+            .collect()
+    }
+
+    /// Prints the access that differs between two hosts.
+    pub fn compare_hosts(
+        &self,
+        first: &str,
+        second: &str,
+        inventory: &Inventory,
+    ) -> anyhow::Result<()> {
+        let pattern_hosts = self.resolve_patterns(inventory)?;
+        let first_access = self.host_access(first, &pattern_hosts);
+        let second_access = self.host_access(second, &pattern_hosts);
+
+        if first_access == second_access {
+            println!("No differences in access between {first} and {second}.");
+            return Ok(());
+        }
+
+        for (host, only) in [
+            (first, first_access.difference(&second_access)),
+            (second, second_access.difference(&first_access)),
+        ] {
+            println!("# Only on {host}:");
+            for (user, role) in only {
+                println!("  + {user} ({role})");
+            }
+            println!();
+        }
+
+        Ok(())
+    }
+
+    /// Returns each user's access as JSON, mapping user names to their access statements.
+    /// Resolved hosts are null if no inventory is given,
+    /// otherwise the distinct patterns are resolved up front on up to `jobs` threads.
+    pub fn display_json(
+        &self,
+        inventory: Option<&Inventory>,
+        jobs: NonZeroUsize,
+    ) -> anyhow::Result<serde_json::Value> {
+        let patterns = self
+            .users
+            .iter()
+            .flat_map(|user| &user.access)
+            .map(|stmt| stmt.hosts.as_str());
+        let pattern_hosts = inventory
+            .map(|inventory| inventory.list_patterns_hosts(patterns, jobs))
+            .transpose()?;
+        let mut users: BTreeMap<&str, Vec<serde_json::Value>> = BTreeMap::new();
+
+        for user in &self.users {
+            for stmt in &user.access {
+                let resolved_hosts = pattern_hosts.as_ref().map(|pattern_hosts| {
+                    pattern_hosts[stmt.hosts.as_str()]
+                        .keys()
+                        .sorted()
+                        .collect::<Vec<_>>()
+                });
+
+                users
+                    .entry(&user.name)
+                    .or_default()
+                    .push(serde_json::json!({
+                        "hosts_pattern": stmt.hosts,
+                        "role": stmt.role,
+                        "groups": stmt.groups,
+                        "seuser": stmt.seuser,
+                        "resolved_hosts": resolved_hosts,
+                    }));
+            }
+        }
+
+        Ok(serde_json::to_value(users)?)
+    }
+
+    /// Prints each user's access, with the hosts for each pattern sorted by name.
+    /// Host patterns are only resolved if an inventory is given,
+    /// in which case the distinct patterns are resolved up front on up to `jobs` threads.
+    pub fn display(&self, inventory: Option<&Inventory>, jobs: NonZeroUsize) {
+        let patterns = self
+            .users
+            .iter()
+            .flat_map(|user| &user.access)
+            .map(|stmt| stmt.hosts.as_str());
+        let pattern_hosts = match inventory.map(|inv| inv.list_patterns_hosts(patterns, jobs)) {
+            Some(Ok(pattern_hosts)) => Some(pattern_hosts),
+            Some(Err(err)) => {
+                println!("{err}");
+                exit(1)
+            }
+            None => None,
+        };
+
         for user in &self.users {
             println!("# User: {}", user.name);
+            if let Some(comment) = &user.comment {
+                println!("  comment: {comment}");
+            }
             for stmt in &user.access {
                 println!("  host pattern: {}", stmt.hosts);
                 println!("  role: {}", stmt.role);
@@ -94,31 +1518,385 @@ impl SSHConfig {
                     println!("  seuser: {seuser}");
                 }
 
-                let hosts = if let Some(hosts_) = pattern_hosts.get(&stmt.hosts) {
-                    hosts_
-                } else {
-                    match subprocess::list_hosts(&stmt.hosts) {
-                        Ok(hosts_) => {
-                            pattern_hosts.insert(&stmt.hosts, hosts_);
-                            pattern_hosts.get(&stmt.hosts).unwrap()
-                        }
-                        Err(err) => {
-                            println!("{err}");
-                            exit(1)
-                        }
-                    }
+                if let Some(become_user) = &stmt.become_user {
+                    println!("  become user: {become_user}");
+                }
+                if stmt.r#become == Some(false) {
+                    println!("  keys authorized without become");
+                }
+
+                let Some(pattern_hosts) = &pattern_hosts else {
+                    println!("\n## Hosts: not resolved in a dry run\n");
+                    continue;
                 };
 
                 println!("\n## Hosts:");
-                for (host, hostname_) in hosts {
-                    print!("  + {host}");
-                    if let Some(hostname) = hostname_ {
-                        print!(" -- ({hostname})");
-                    }
-                    println!();
+                for (host, hostname) in pattern_hosts[stmt.hosts.as_str()].iter().sorted() {
+                    println!("  + {host} -- ({hostname})");
                 }
                 println!();
             }
         }
     }
+
+    /// Creates a draft config from the keys found on hosts, with each user given the role on
+    /// the hosts their keys were found on. Keys found on more than one host are only listed once.
+    /// Keys for root are skipped, as managing root as a user would lock its password.
+    pub fn from_found_keys(found: &[FoundKeys], role: Role) -> SSHConfig {
+        let mut user_keys: BTreeMap<&str, (Vec<&str>, BTreeSet<&str>)> = BTreeMap::new();
+        for entry in found.iter().filter(|entry| entry.user != "root") {
+            let (keys, hosts) = user_keys.entry(&entry.user).or_default();
+            keys.extend(entry.keys.iter().map(String::as_str));
+            hosts.insert(&entry.host);
+        }
+
+        let users = user_keys
+            .into_iter()
+            .map(|(name, (keys, hosts))| SSHUser {
+                name: name.to_string(),
+                comment: None,
+                uid: None,
+                pubkey_files: vec![],
+                pubkeys: keys
+                    .into_iter()
+                    .unique_by(|key| keys::normalize_key(key).unwrap_or(key.to_string()))
+                    .map(|key| PubKey {
+                        key: key.to_string(),
+                        state: None,
+                        options: None,
+                        comment: None,
+                    })
+                    .collect(),
+                access: vec![AccessStmt {
+                    hosts: hosts.into_iter().join(":"),
+                    role: role.clone(),
+                    groups: vec![],
+                    seuser: None,
+                    umask: None,
+                    shell: None,
+                    home: None,
+                    key_options: None,
+                    exclusive: None,
+                    local: None,
+                    generate_ssh_key: None,
+                    ssh_key_bits: None,
+                    ssh_key_type: None,
+                    expires: None,
+                    trusted_cas: vec![],
+                    gather_facts: false,
+                    become_user: None,
+                    r#become: None,
+                    password_hash: None,
+                    authorized_keys_path: None,
+                    remove_home: None,
+                    primary_group: None,
+                    pubkeys: None,
+                    when: None,
+                }],
+            })
+            .collect();
+
+        SSHConfig {
+            users,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the users that were added, changed or removed since the old config.
+    pub fn diff(&self, old: &SSHConfig) -> ChangeSet {
+        let mut changes = ChangeSet::default();
+        for user in &self.users {
+            match old.users.iter().find(|old_user| old_user.name == user.name) {
+                None => {
+                    changes.added.insert(user.name.clone());
+                }
+                Some(old_user) if old_user != user => {
+                    changes.changed.insert(user.name.clone());
+                }
+                Some(_) => {}
+            }
+        }
+        changes.removed = old
+            .users
+            .iter()
+            .filter(|old_user| !self.users.iter().any(|user| user.name == old_user.name))
+            .cloned()
+            .collect();
+
+        changes
+    }
+
+    /// Returns a copy of the config with only the users that were added or changed.
+    pub fn only_changed(&self, changes: &ChangeSet) -> SSHConfig {
+        SSHConfig {
+            users: self
+                .users
+                .iter()
+                .filter(|user| {
+                    changes.added.contains(&user.name) || changes.changed.contains(&user.name)
+                })
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Counts the users, host patterns and keys the config manages.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            users: self.users.len(),
+            host_patterns: self
+                .users
+                .iter()
+                .flat_map(|user| &user.access)
+                .map(|stmt| &stmt.hosts)
+                .unique()
+                .count(),
+            keys: self
+                .users
+                .iter()
+                .filter(|user| user.access.iter().any(|stmt| stmt.role.authorizes_keys()))
+                .map(|user| user.present_keys().count())
+                .sum(),
+        }
+    }
+
+    /// Returns the authorized_keys content for each user, or just the named user,
+    /// with a comment listing the host patterns it is authorized on.
+    pub fn keys_report(&self, name: Option<&str>) -> Result<String, UnknownUserError> {
+        let users = self
+            .users
+            .iter()
+            .filter(|user| name.is_none_or(|name| user.name == name))
+            .collect::<Vec<_>>();
+        if let (Some(name), true) = (name, users.is_empty()) {
+            return Err(UnknownUserError {
+                name: name.to_string(),
+            });
+        }
+
+        let mut report = String::new();
+        for user in users {
+            report.push_str(&format!("# {}\n", user.name));
+            for stmt in &user.access {
+                report.push_str(&format!("# {} as {}", stmt.hosts, stmt.role));
+                if stmt.role == Role::Blocked {
+                    report.push_str(", keys removed");
+                } else if stmt.role == Role::Absent {
+                    report.push_str(", account removed");
+                } else if let Some(options) = &stmt.key_options {
+                    report.push_str(&format!(", with options {options}"));
+                }
+                report.push('\n');
+
+                // Keys of the statement itself are listed under it, instead of the user's keys.
+                let keys = user.authorized_keys(stmt);
+                if stmt.pubkeys.is_some() && !keys.is_empty() && stmt.role.authorizes_keys() {
+                    report.push_str(&keys);
+                    report.push('\n');
+                }
+            }
+
+            // Blocked users' keys are only listed to remove them.
+            let keys = user
+                .access
+                .iter()
+                .find(|stmt| stmt.role.authorizes_keys() && stmt.pubkeys.is_none())
+                .map(|stmt| user.authorized_keys(stmt))
+                .unwrap_or_default();
+            if !keys.is_empty() {
+                report.push_str(&keys);
+                report.push('\n');
+            }
+            report.push('\n');
+        }
+
+        Ok(report)
+    }
+}
+
+/// Reads and parses the config file, or stdin if the path is `-`.
+pub fn read_config(path: &str) -> Result<SSHConfig, InvalidConfigError> {
+    let (name, content) = if path == "-" {
+        ("stdin", io::read_to_string(io::stdin()))
+    } else {
+        (path, fs::read_to_string(path))
+    };
+    let content = content.map_err(|err| InvalidConfigError {
+        message: format!("failed to read {name}: {err}"),
+    })?;
+
+    // Key files and includes in a config read from stdin are relative to the working directory.
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    // Configs without includes are parsed directly, for errors with line numbers.
+    let mut conf = match serde_yaml::from_str::<serde_yaml::Value>(&content) {
+        Ok(mut value) if has_includes(&value) => {
+            let mut stack = match path {
+                "-" => vec![],
+                _ => vec![canonical_path(Path::new(path))?],
+            };
+            resolve_includes(&mut value, base, &mut stack)?;
+            serde_yaml::from_value(value).map_err(|err| InvalidConfigError {
+                message: format!("failed to parse {name} with its includes: {err}"),
+            })?
+        }
+        _ => parse_config(name, &content)?,
+    };
+    conf.expand_vars(|name| env::var(name).ok())?;
+    conf.read_pubkey_files(base)?;
+    Ok(conf)
+}
+
+/// Reads and merges each config file, then expands key groups in the merged config,
+/// so a key group defined in one file can be referenced from another.
+pub fn read_configs(paths: &[String]) -> Result<SSHConfig, InvalidConfigError> {
+    let mut conf = SSHConfig::default();
+    for path in paths {
+        conf = conf.merge(read_config(path)?)?;
+    }
+    conf.expand_key_groups()?;
+    Ok(conf)
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}` in the value, and `$${` with `${`.
+/// Any other `$` is left as it is, so e.g. crypt hashes are unchanged.
+/// Returns a description of the problem if a variable is undefined or a reference is malformed.
+fn expand_vars(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("has an unclosed variable reference in {value}"));
+        };
+        let reference = &rest[start + 2..start + len];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("has an invalid variable name {name:?} in {value}"));
+        }
+
+        match lookup(name).or(default.map(str::to_string)) {
+            Some(var) => expanded.push_str(&var),
+            None => return Err(format!("references undefined variable {name}")),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Returns whether the value contains an `!include` tag.
+fn has_includes(value: &serde_yaml::Value) -> bool {
+    match value {
+        serde_yaml::Value::Tagged(tagged) => tagged.tag == "include" || has_includes(&tagged.value),
+        serde_yaml::Value::Sequence(items) => items.iter().any(has_includes),
+        serde_yaml::Value::Mapping(map) => map.values().any(has_includes),
+        _ => false,
+    }
+}
+
+/// Replaces each `!include path` in the value with the YAML in that file.
+/// Paths are relative to the directory of the file that includes them.
+/// The stack holds the files currently being included, so cycles can be detected.
+fn resolve_includes(
+    value: &mut serde_yaml::Value,
+    base: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<(), InvalidConfigError> {
+    match value {
+        serde_yaml::Value::Tagged(tagged) if tagged.tag == "include" => {
+            let Some(include) = tagged.value.as_str() else {
+                return Err(InvalidConfigError {
+                    message: format!("!include needs a path, not {:?}", tagged.value),
+                });
+            };
+            let path = canonical_path(&base.join(include))?;
+            if stack.contains(&path) {
+                return Err(InvalidConfigError {
+                    message: format!(
+                        "include cycle: {} -> {}",
+                        stack.iter().map(|path| path.display()).join(" -> "),
+                        path.display()
+                    ),
+                });
+            }
+
+            let name = path.display().to_string();
+            let content = fs::read_to_string(&path).map_err(|err| InvalidConfigError {
+                message: format!("failed to read {name}: {err}"),
+            })?;
+            let mut included =
+                serde_yaml::from_str(&content).map_err(|err| InvalidConfigError {
+                    message: format!("failed to parse {name}: {err}"),
+                })?;
+
+            let dir = path.parent().unwrap_or(base).to_path_buf();
+            stack.push(path);
+            resolve_includes(&mut included, &dir, stack)?;
+            stack.pop();
+            *value = included;
+        }
+        serde_yaml::Value::Tagged(tagged) => resolve_includes(&mut tagged.value, base, stack)?,
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                resolve_includes(item, base, stack)?;
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for item in map.values_mut() {
+                resolve_includes(item, base, stack)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Returns the canonical path of a config file, so includes of the same file can be compared.
+fn canonical_path(path: &Path) -> Result<PathBuf, InvalidConfigError> {
+    fs::canonicalize(path).map_err(|err| InvalidConfigError {
+        message: format!("failed to read {}: {err}", path.display()),
+    })
+}
+
+/// Parses the content of a config file, using the name to describe where it came from.
+pub fn parse_config(path: &str, content: &str) -> Result<SSHConfig, InvalidConfigError> {
+    if content.trim().is_empty() {
+        return Err(InvalidConfigError {
+            message: format!("{path} is empty"),
+        });
+    }
+
+    serde_yaml::from_str(content).map_err(|err| InvalidConfigError {
+        message: match err.location() {
+            Some(loc) => format!(
+                "failed to parse {path} at line {}, column {}: {}",
+                loc.line(),
+                loc.column(),
+                // serde_yaml appends the location to its own message, so strip it to avoid repeating it.
+                err.to_string().trim_end_matches(&format!(
+                    " at line {} column {}",
+                    loc.line(),
+                    loc.column()
+                ))
+            ),
+            None => format!("failed to parse {path}: {err}"),
+        },
+    })
 }
@@ -1,4 +1,4 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, io, process::ExitStatus, time::Duration};
 
 #[derive(Debug)]
 pub struct UndefinedGroupError {
@@ -9,7 +9,37 @@ impl Error for UndefinedGroupError {}
 
 impl Display for UndefinedGroupError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Undefined section: {}", self.name)
+        write!(f, "Undefined group or host: {}", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownUserError {
+    pub name: String,
+}
+
+impl Error for UnknownUserError {}
+
+impl Display for UnknownUserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No user named {} in the config", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidRoleError {
+    pub role: String,
+}
+
+impl Error for InvalidRoleError {}
+
+impl Display for InvalidRoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid role {}; expected blocked, disabled, sudoer, nopass, superuser or absent",
+            self.role
+        )
     }
 }
 
@@ -26,9 +56,32 @@ impl Display for InvalidConfigError {
     }
 }
 
+/// Bytes of ansible-inventory output to include in a parse error.
+const RAW_OUTPUT_LEN: usize = 300;
+
 #[derive(Debug)]
 pub struct InvOutputParseError {
     pub message: String,
+    /// The start of the output, e.g. to show a warning ansible printed instead of the inventory.
+    pub raw: String,
+}
+
+impl InvOutputParseError {
+    /// Creates an error with the start of the output, dropping any bytes that aren't UTF-8.
+    pub fn new(message: String, output: &[u8]) -> Self {
+        let output = String::from_utf8_lossy(output).replace(char::REPLACEMENT_CHARACTER, "");
+        let mut raw = output.trim_end().to_string();
+        if raw.len() > RAW_OUTPUT_LEN {
+            let end = (0..=RAW_OUTPUT_LEN)
+                .rev()
+                .find(|&i| raw.is_char_boundary(i))
+                .unwrap_or_default();
+            raw.truncate(end);
+            raw.push_str("...");
+        }
+
+        Self { message, raw }
+    }
 }
 
 impl Error for InvOutputParseError {}
@@ -39,6 +92,216 @@ impl Display for InvOutputParseError {
             f,
             "Failed to parse ansible-inventory output; {}",
             self.message
+        )?;
+        if !self.raw.is_empty() {
+            write!(f, "\nThe output began with:\n{}", self.raw)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct InventoryTimeoutError {
+    pub timeout: Duration,
+}
+
+impl Error for InventoryTimeoutError {}
+
+impl Display for InventoryTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ansible-inventory timed out after {}s; an inventory plugin may be stalled. \
+            Pass a longer --inventory-timeout or a YAML inventory with -i",
+            self.timeout.as_secs_f64()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct TransformError {
+    pub command: String,
+    pub status: ExitStatus,
+}
+
+impl Error for TransformError {}
+
+impl Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Transform command `{}` failed; {}",
+            self.command, self.status
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct PlaybookTestError {
+    /// The stage of the test that failed, e.g. "syntax check".
+    pub stage: &'static str,
+    pub status: ExitStatus,
+    /// What ansible printed to stdout and stderr.
+    pub output: String,
+}
+
+impl Error for PlaybookTestError {}
+
+impl Display for PlaybookTestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Playbook failed the {}; {}\n{}",
+            self.stage, self.status, self.output
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKrlError {
+    pub path: String,
+    pub line: String,
+}
+
+impl Error for InvalidKrlError {}
+
+impl Display for InvalidKrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid key revocation list {}; not a fingerprint or public key: {}",
+            self.path, self.line
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidReportError {
+    pub report: String,
+}
+
+impl Error for InvalidReportError {}
+
+impl Display for InvalidReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid report {}; expected FORMAT:PATH e.g. junit:report.xml",
+            self.report
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidLimitError {
+    pub limit: String,
+}
+
+impl Error for InvalidLimitError {}
+
+impl Display for InvalidLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid limit {}; expected one group or host, optionally with & and ! terms e.g. web:!web3",
+            self.limit
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKeyError {
+    pub key: String,
+    /// What is wrong with the key, as described by [`crate::keys::parse_key`].
+    pub reason: String,
+}
+
+impl Error for InvalidKeyError {}
+
+impl Display for InvalidKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid public key ({}): {}", self.reason, self.key)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidExtraVarError {
+    pub var: String,
+}
+
+impl Error for InvalidExtraVarError {}
+
+impl Display for InvalidExtraVarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid extra var {}; expected KEY=VALUE where KEY is a valid variable name",
+            self.var
+        )
+    }
+}
+
+/// Why ansible-playbook didn't run to completion.
+#[derive(Debug)]
+pub enum PlaybookFailure {
+    /// The process couldn't be spawned or waited on.
+    Spawn(io::Error),
+    /// The process ran for longer than the timeout and was killed.
+    Timeout(Duration),
+    /// The process ran, but its output couldn't be read.
+    Output(io::Error),
+}
+
+#[derive(Debug)]
+pub struct PlaybookError {
+    pub attempts: u32,
+    pub failure: PlaybookFailure,
+}
+
+impl Error for PlaybookError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.failure {
+            PlaybookFailure::Spawn(err) | PlaybookFailure::Output(err) => Some(err),
+            PlaybookFailure::Timeout(_) => None,
+        }
+    }
+}
+
+impl Display for PlaybookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.failure {
+            PlaybookFailure::Spawn(err) => write!(
+                f,
+                "Failed to run ansible-playbook after {} attempts; {err}",
+                self.attempts
+            ),
+            PlaybookFailure::Timeout(timeout) => write!(
+                f,
+                "ansible-playbook timed out after {}s on each of {} attempts",
+                timeout.as_secs_f64(),
+                self.attempts
+            ),
+            PlaybookFailure::Output(err) => {
+                write!(f, "Failed to read the output of ansible-playbook; {err}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SudoersError {
+    pub group: String,
+    pub output: String,
+}
+
+impl Error for SudoersError {}
+
+impl Display for SudoersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "visudo rejected the sudoers file for {}; {}",
+            self.group, self.output
         )
     }
 }
@@ -0,0 +1,78 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use crate::config::{Role, SSHConfig};
+
+/// Returns the colour of the edges for users with a role.
+fn role_colour(role: &Role) -> &'static str {
+    match role {
+        Role::Blocked => "gray",
+        Role::Disabled => "orange",
+        Role::Sudoer => "blue",
+        Role::Nopass => "purple",
+        Role::SuperUser => "red",
+        Role::Absent => "black",
+    }
+}
+
+/// Quotes a string as a DOT ID.
+fn quote(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns a Graphviz DOT graph with an edge from each user to each host they can access,
+/// labelled and coloured by their role there.
+/// `pattern_hosts` maps host patterns to the hosts they match, as from
+/// [`SSHConfig::resolve_patterns`].
+pub fn to_dot(conf: &SSHConfig, pattern_hosts: &HashMap<&str, HashMap<String, String>>) -> String {
+    let mut edges = BTreeSet::new();
+    for user in &conf.users {
+        for stmt in &user.access {
+            let hosts = pattern_hosts.get(stmt.hosts.as_str());
+            for host in hosts.into_iter().flat_map(HashMap::keys) {
+                edges.insert((user.name.as_str(), host.as_str(), &stmt.role));
+            }
+        }
+    }
+    let hosts = edges
+        .iter()
+        .map(|(_, host, _)| *host)
+        .collect::<BTreeSet<_>>();
+
+    let mut dot = String::from("digraph access {\n    rankdir=LR;\n");
+    for user in &conf.users {
+        // User and host names are prefixed so a user and a host with the same name are distinct.
+        writeln!(
+            dot,
+            "    {} [label={}, shape=box];",
+            quote(&format!("user:{}", user.name)),
+            quote(&user.name)
+        )
+        .unwrap();
+    }
+    for host in hosts {
+        writeln!(
+            dot,
+            "    {} [label={}, shape=ellipse];",
+            quote(&format!("host:{host}")),
+            quote(host)
+        )
+        .unwrap();
+    }
+    for (user, host, role) in edges {
+        writeln!(
+            dot,
+            "    {} -> {} [label={}, color={}];",
+            quote(&format!("user:{user}")),
+            quote(&format!("host:{host}")),
+            quote(&role.to_string()),
+            role_colour(role)
+        )
+        .unwrap();
+    }
+    dot.push_str("}\n");
+
+    dot
+}
@@ -0,0 +1,48 @@
+use std::{collections::BTreeMap, fs};
+
+use anyhow::Context;
+use log::debug;
+use serde::de::DeserializeOwned;
+
+use crate::config::{AccessStmt, SSHUser};
+
+/// Access statements granted to the members of each group in an identity source, e.g. LDAP.
+/// Written as a mapping of group names to access statements, as in a user's `access`.
+pub type GroupAccess = BTreeMap<String, Vec<AccessStmt>>;
+
+/// Names of the identity groups each user is a member of.
+pub type Memberships = BTreeMap<String, Vec<String>>;
+
+/// Reads a YAML or JSON file, such as a group access mapping or memberships file.
+pub fn read<T: DeserializeOwned>(path: &str) -> anyhow::Result<T> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    serde_yaml::from_str(&content).with_context(|| format!("Failed to parse {path}"))
+}
+
+/// Gives each user the access statements of the identity groups they are a member of,
+/// after any access they have in the config.
+/// Groups without an access mapping grant nothing, and a statement granted by more than
+/// one group is only added once.
+///
+/// Returns the members that aren't users in the config, which are left out as they have no keys.
+pub fn derive_access(
+    users: &mut [SSHUser],
+    groups: &GroupAccess,
+    memberships: &Memberships,
+) -> Vec<String> {
+    for user in users.iter_mut() {
+        let member_of = memberships.get(&user.name).into_iter().flatten();
+        for stmt in member_of.filter_map(|group| groups.get(group)).flatten() {
+            if !user.access.contains(stmt) {
+                debug!("Giving {} {} on {}", user.name, stmt.role, stmt.hosts);
+                user.access.push(stmt.clone());
+            }
+        }
+    }
+
+    memberships
+        .keys()
+        .filter(|name| !users.iter().any(|user| &user.name == *name))
+        .cloned()
+        .collect()
+}
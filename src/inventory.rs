@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    num::NonZeroUsize,
+    thread,
+};
+
+use itertools::Itertools;
+use log::{debug, trace, warn};
+use regex::Regex;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::error::UndefinedGroupError;
+
+/// A group as written in a YAML inventory.
+#[derive(Debug, Default, Deserialize)]
+struct GroupDef {
+    #[serde(default)]
+    hosts: Option<HashMap<String, Option<Value>>>,
+    #[serde(default)]
+    children: Option<HashMap<String, Option<GroupDef>>>,
+}
+
+/// Models a group of hosts in an inventory.
+#[derive(Debug, Default)]
+pub struct Group {
+    /// Hosts directly in this group, and their vars.
+    pub hosts: HashMap<String, Value>,
+    /// Names of the groups that are children of this group.
+    pub children: HashSet<String>,
+}
+
+/// Models an ansible inventory, as written in YAML or printed by `ansible-inventory --list --yaml`.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub groups: HashMap<String, Group>,
+}
+
+impl Inventory {
+    /// Reads an inventory from a YAML file.
+    pub fn read(path: &str) -> anyhow::Result<Self> {
+        Self::from_yaml(&fs::read_to_string(path)?)
+    }
+
+    /// Parses an inventory from YAML.
+    pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> {
+        let root: HashMap<String, Option<GroupDef>> = serde_yaml::from_str(yaml)?;
+
+        let mut inventory = Self::default();
+        for (name, group) in root {
+            inventory.add_group(name, group.unwrap_or_default());
+        }
+        debug!(
+            "Parsed inventory with {} groups and {} hosts",
+            inventory.groups.len(),
+            inventory.all_hosts().len()
+        );
+
+        Ok(inventory)
+    }
+
+    /// Adds the group and its children, merging with any group of the same name.
+    fn add_group(&mut self, name: String, def: GroupDef) {
+        let mut children = HashSet::new();
+        for (child_name, child) in def.children.unwrap_or_default() {
+            children.insert(child_name.clone());
+            self.add_group(child_name, child.unwrap_or_default());
+        }
+
+        let group = self.groups.entry(name).or_default();
+        group.children.extend(children);
+        for (host, vars) in def.hosts.unwrap_or_default() {
+            let host_vars = group.hosts.entry(host).or_insert(Value::Null);
+            if host_vars.is_null() {
+                *host_vars = vars.unwrap_or(Value::Null);
+            }
+        }
+    }
+
+    /// Returns every host in the inventory.
+    pub fn all_hosts(&self) -> HashSet<String> {
+        self.groups
+            .values()
+            .flat_map(|group| group.hosts.keys().cloned())
+            .collect()
+    }
+
+    /// Returns the hosts that aren't in any group except `all` and `ungrouped`.
+    /// ansible-inventory lists these under an `ungrouped` group, but inventory files may not.
+    pub fn ungrouped_hosts(&self) -> HashSet<String> {
+        let grouped = self
+            .groups
+            .iter()
+            .filter(|(name, _)| *name != "all" && *name != "ungrouped")
+            .flat_map(|(_, group)| group.hosts.keys())
+            .collect::<HashSet<_>>();
+
+        self.all_hosts()
+            .into_iter()
+            .filter(|host| !grouped.contains(host))
+            .collect()
+    }
+
+    /// Returns the hosts in the group and all of its descendants.
+    pub fn group_hosts(&self, name: &str) -> Option<HashSet<String>> {
+        let mut hosts = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut queue = vec![self.groups.get(name)?];
+        visited.insert(name);
+
+        while let Some(group) = queue.pop() {
+            hosts.extend(group.hosts.keys().cloned());
+            for child in &group.children {
+                if visited.insert(child) {
+                    queue.extend(self.groups.get(child));
+                }
+            }
+        }
+
+        Some(hosts)
+    }
+
+    /// Returns the vars for a host, merged from each group it is directly in.
+    pub fn host_vars(&self, host: &str) -> Value {
+        let mut vars = serde_yaml::Mapping::new();
+        for group in self.groups.values() {
+            match group.hosts.get(host) {
+                Some(Value::Mapping(group_vars)) => {
+                    for (key, value) in group_vars {
+                        vars.entry(key.clone()).or_insert_with(|| value.clone());
+                    }
+                }
+                Some(Value::Null) | None => {}
+                Some(other) => trace!("Skipping vars for {host} that aren't a mapping: {other:?}"),
+            }
+        }
+        Value::Mapping(vars)
+    }
+
+    /// Returns the hosts matched by an ansible host pattern.
+    /// Terms are separated by `:` or `,`; terms prefixed with `&` are intersected,
+    /// and terms prefixed with `!` are excluded.
+    /// A pattern with only `&` and `!` terms applies them to all hosts, as ansible does.
+    /// Terms may be globs using `*` and `?`, matched against host and group names,
+    /// or regexes prefixed with `~`, matched against host names.
+    pub fn get_pattern_hosts(&self, pattern: &str) -> Result<HashSet<String>, UndefinedGroupError> {
+        let mut included = HashSet::new();
+        let mut has_included = false;
+        let mut intersections = vec![];
+        let mut exclusions = vec![];
+
+        for term in pattern.split([':', ',']).map(str::trim) {
+            if let Some(name) = term.strip_prefix('&') {
+                intersections.push(self.get_term_hosts(name)?);
+            } else if let Some(name) = term.strip_prefix('!') {
+                exclusions.push(self.get_term_hosts(name)?);
+            } else if !term.is_empty() {
+                has_included = true;
+                included.extend(self.get_term_hosts(term)?);
+            }
+        }
+
+        if !has_included {
+            included = self.all_hosts();
+        }
+
+        for hosts in intersections {
+            included.retain(|host| hosts.contains(host));
+        }
+        for hosts in exclusions {
+            included.retain(|host| !hosts.contains(host));
+        }
+
+        Ok(included)
+    }
+
+    /// Returns the hosts matched by an ansible host pattern and their hostname or address.
+    pub fn list_hosts(
+        &self,
+        pattern: &str,
+    ) -> Result<HashMap<String, String>, UndefinedGroupError> {
+        let hosts = self.get_pattern_hosts(pattern)?;
+        debug!("Host pattern {pattern} matched {} hosts", hosts.len());
+
+        Ok(hosts
+            .into_iter()
+            .map(|host| {
+                let hostname = self.hostname(&host);
+                (host, hostname)
+            })
+            .collect())
+    }
+
+    /// Returns the hosts matched by each distinct pattern and their hostname or address,
+    /// resolving the patterns on up to `jobs` threads.
+    /// If any pattern fails to resolve the error for the first in order is returned.
+    pub fn list_patterns_hosts<'a>(
+        &self,
+        patterns: impl IntoIterator<Item = &'a str>,
+        jobs: NonZeroUsize,
+    ) -> Result<HashMap<&'a str, HashMap<String, String>>, UndefinedGroupError> {
+        let patterns = patterns.into_iter().unique().collect_vec();
+        let chunk_size = patterns.len().div_ceil(jobs.get()).max(1);
+
+        thread::scope(|scope| {
+            let workers = patterns
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|pattern| Ok((*pattern, self.list_hosts(pattern)?)))
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                })
+                .collect_vec();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("Failed to join pattern resolver."))
+                .flatten_ok()
+                .collect()
+        })
+    }
+
+    /// Returns the hostname or address for a host from its vars.
+    /// Falls back to the inventory name of the host, which is often its address.
+    pub fn hostname(&self, host: &str) -> String {
+        let vars = self.host_vars(host);
+        [
+            "ansible_hostname",
+            "inventory_hostname",
+            "ansible_host",
+            "ansible_ssh_host",
+            "address",
+        ]
+        .iter()
+        .find_map(|key| vars[key].as_str())
+        .unwrap_or(host)
+        .to_string()
+    }
+
+    /// Returns the hosts whose names match the glob, and the hosts in groups whose names match.
+    fn glob_hosts(&self, glob: &str) -> HashSet<String> {
+        let mut hosts = self
+            .all_hosts()
+            .into_iter()
+            .filter(|host| glob_match(glob, host))
+            .collect::<HashSet<_>>();
+
+        for name in self.groups.keys().filter(|name| glob_match(glob, name)) {
+            hosts.extend(self.group_hosts(name).unwrap_or_default());
+        }
+
+        hosts
+    }
+
+    /// Returns the hosts whose names match the regex from their start, like ansible's `~` patterns.
+    /// An invalid regex is logged and matches no hosts.
+    fn regex_hosts(&self, regex: &str) -> HashSet<String> {
+        match Regex::new(&format!("^(?:{regex})")) {
+            Ok(regex) => self
+                .all_hosts()
+                .into_iter()
+                .filter(|host| regex.is_match(host))
+                .collect(),
+            Err(err) => {
+                warn!("Invalid regex in host pattern ~{regex}: {err}");
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Returns the hosts matched by a single term of a pattern; a group or host name.
+    fn get_term_hosts(&self, name: &str) -> Result<HashSet<String>, UndefinedGroupError> {
+        if name == "all" || name == "*" {
+            return Ok(self.all_hosts());
+        }
+        if name == "ungrouped" {
+            return Ok(self.ungrouped_hosts());
+        }
+        if let Some(regex) = name.strip_prefix('~') {
+            return Ok(self.regex_hosts(regex));
+        }
+        if name.contains(['*', '?']) {
+            return Ok(self.glob_hosts(name));
+        }
+
+        if let Some(hosts) = self.group_hosts(name) {
+            Ok(hosts)
+        } else if self
+            .groups
+            .values()
+            .any(|group| group.hosts.contains_key(name))
+        {
+            Ok(HashSet::from([name.to_string()]))
+        } else {
+            Err(UndefinedGroupError {
+                name: name.to_string(),
+            })
+        }
+    }
+}
+
+/// Returns whether the name matches the glob, where `*` matches any run of characters
+/// and `?` matches any single character.
+fn glob_match(glob: &str, name: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut g, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` match one more character and try again.
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    g = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
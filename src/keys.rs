@@ -0,0 +1,134 @@
+use std::{collections::HashSet, fs};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::InvalidKrlError;
+
+/// Key types that may appear in an authorized_keys file.
+pub const KEY_TYPES: [&str; 8] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// A public key line from an authorized_keys file, split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedKey<'a> {
+    /// Options that restrict the key, e.g. `from="10.0.0.1",no-pty`.
+    pub options: Option<&'a str>,
+    pub key_type: &'a str,
+    /// Base64 encoded key data.
+    pub body: &'a str,
+    pub comment: Option<&'a str>,
+}
+
+/// Parses a public key line as it would appear in an authorized_keys file.
+/// Returns a description of the problem if the line is not a valid key.
+pub fn parse_key(line: &str) -> Result<ParsedKey<'_>, String> {
+    let line = line.trim();
+    let (options, rest) = match line.split_once(char::is_whitespace) {
+        Some((first, _)) if KEY_TYPES.contains(&first) => (None, line),
+        None if KEY_TYPES.contains(&line) => return Err("missing key data".to_string()),
+        _ => {
+            let (options, rest) = split_options(line);
+            (Some(options), rest)
+        }
+    };
+
+    let mut parts = rest.splitn(3, char::is_whitespace);
+    let key_type = parts.next().unwrap_or_default();
+    if !KEY_TYPES.contains(&key_type) {
+        return Err("no recognized key type".to_string());
+    }
+
+    let body = parts.next().unwrap_or_default().trim();
+    if body.is_empty() {
+        return Err("missing key data".to_string());
+    }
+
+    // The decoded key data starts with the length prefixed key type.
+    let blob = STANDARD
+        .decode(body)
+        .map_err(|err| format!("invalid base64 key data; {err}"))?;
+    let embedded_type = blob
+        .get(..4)
+        .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+        .and_then(|len| blob.get(4..4 + len));
+    if embedded_type != Some(key_type.as_bytes()) {
+        return Err(format!("key data does not contain a {key_type} key"));
+    }
+
+    Ok(ParsedKey {
+        options,
+        key_type,
+        body,
+        comment: parts.next().map(str::trim).filter(|c| !c.is_empty()),
+    })
+}
+
+/// Returns the key type and data of a key line, without any options or comment.
+/// Lines that are the same key with different options or comments normalize to the same string.
+pub fn normalize_key(line: &str) -> Option<String> {
+    let key = parse_key(line).ok()?;
+    Some(format!("{} {}", key.key_type, key.body))
+}
+
+/// Splits the options from the start of a key line, respecting double quotes.
+fn split_options(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return (&line[..i], line[i..].trim_start()),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Returns the SHA256 fingerprint of a public key, in the format printed by `ssh-keygen -l`.
+pub fn fingerprint(key: &str) -> Option<String> {
+    let blob = STANDARD.decode(parse_key(key).ok()?.body).ok()?;
+    Some(format!(
+        "SHA256:{}",
+        STANDARD_NO_PAD.encode(Sha256::digest(blob))
+    ))
+}
+
+/// Reads a key revocation list and returns the fingerprints it contains.
+/// Each line is either a fingerprint or a public key. Blank lines and comments are ignored.
+pub fn read_krl(path: &str) -> anyhow::Result<HashSet<String>> {
+    let mut revoked = HashSet::new();
+
+    for line in fs::read_to_string(path)?.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with("SHA256:") {
+            revoked.insert(line.to_string());
+        } else if let Some(fingerprint) = fingerprint(line) {
+            revoked.insert(fingerprint);
+        } else {
+            return Err(InvalidKrlError {
+                path: path.to_string(),
+                line: line.to_string(),
+            }
+            .into());
+        }
+    }
+
+    Ok(revoked)
+}
@@ -0,0 +1,41 @@
+//! Generates Ansible playbooks that manage SSH access from a simple YAML config.
+//!
+//! The config is a list of users, each with their public keys and the hosts they can access:
+//!
+//! ```
+//! use ansible_sshman::{PlayOptions, SSHConfig};
+//!
+//! let conf: SSHConfig = serde_yaml::from_str(
+//!     "
+//! - name: alice
+//!   pubkeys:
+//!     - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc
+//!   access:
+//!     - hosts: web
+//!       role: sudoer
+//! ",
+//! )
+//! .unwrap();
+//!
+//! let plays = conf.create_accounts(&PlayOptions::default());
+//! let playbook = serde_yaml::to_string(&plays).unwrap();
+//! assert!(playbook.contains("Create accounts for alice."));
+//! ```
+
+pub mod config;
+pub mod error;
+pub mod graph;
+pub mod identity;
+pub mod inventory;
+pub mod keys;
+pub mod model;
+mod modules;
+pub mod plays;
+pub mod report;
+pub mod subprocess;
+#[cfg(test)]
+mod tests;
+
+pub use config::{AccessStmt, Role, SSHConfig, SSHUser};
+pub use model::{AnsibleModule, AnsiblePlay, AnsibleTask};
+pub use plays::PlayOptions;
@@ -1,24 +1,174 @@
-mod config;
-mod error;
-mod model;
-mod modules;
-mod plays;
-mod subprocess;
-#[cfg(test)]
-mod tests;
-
-use clap::{Parser, Subcommand};
-use config::SSHConfig;
-use model::AnsiblePlay;
-use std::fs;
-use subprocess::run_plays;
+use ansible_sshman::{
+    config::{read_configs, CheckOptions, Connection, Role, SSHConfig, Severity},
+    graph,
+    identity::{self, GroupAccess, Memberships},
+    inventory::Inventory,
+    keys,
+    model::AnsiblePlay,
+    plays::{HostLimit, PlayOptions},
+    report::{self, Report, UserDiff, ValidationReport},
+    subprocess::{self, run_plays, run_plays_json, ExtraVar, RunOptions},
+};
+use anyhow::Context;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use itertools::Itertools;
+use log::{info, LevelFilter};
+use schemars::schema_for;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Path, PathBuf},
+    process::{exit, ExitStatus},
+    slice,
+    time::Duration,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Path to ssh config file.
+    /// Path to ssh config file, or - to read it from stdin.
+    /// May be given more than once to merge the users from several files.
+    /// Required for every command except import, revoke-key and schema.
     #[clap(short, long, value_parser)]
-    config: String,
+    config: Vec<String>,
+
+    /// Shell command to pipe the generated playbook through before it is run or written.
+    #[clap(long, value_parser)]
+    transform: Option<String>,
+
+    /// Path to a key revocation list; one key fingerprint or public key per line.
+    #[clap(long, value_parser)]
+    krl: Option<String>,
+
+    /// Path to a YAML inventory to check that each host pattern matches some hosts.
+    /// Without it, run, validate and diff check patterns against the output of ansible-inventory,
+    /// and display, compare-hosts, graph and uncovered resolve patterns with ansible-inventory.
+    #[clap(short, long, value_parser)]
+    inventory: Option<String>,
+
+    /// Allow host patterns that match no hosts, e.g. for groups that are intentionally empty.
+    #[clap(long)]
+    allow_empty_patterns: bool,
+
+    /// Allow more than one user to be a superuser on the same host,
+    /// each with their own uid 0 account.
+    #[clap(long)]
+    allow_multiple_superusers: bool,
+
+    /// Render the sudoers files once per play rather than once per host.
+    #[clap(long)]
+    run_once: bool,
+
+    /// How plays become root, e.g. doas or su. Ansible's default, sudo, is used if not set.
+    #[clap(long, value_parser)]
+    become_method: Option<String>,
+
+    /// Connection plugin to manage hosts with, e.g. local or docker, to test a config against
+    /// this machine or a container. Written to each play, and passed to ansible-playbook.
+    #[clap(long, value_parser)]
+    connection: Option<String>,
+
+    /// Restrict every play to hosts matching this pattern, e.g. a canary group.
+    #[clap(long, value_parser)]
+    limit: Option<HostLimit>,
+
+    /// Remote user to connect to hosts as, overriding the config's connection settings.
+    #[clap(long, value_parser)]
+    remote_user: Option<String>,
+
+    /// Port to connect to hosts on, overriding the config's connection settings.
+    #[clap(long, value_parser)]
+    port: Option<u16>,
+
+    /// Private key to connect to hosts with, overriding the config's connection settings.
+    #[clap(long, value_parser)]
+    private_key: Option<String>,
+
+    /// Number of hosts ansible-playbook manages in parallel, overriding forks in the config.
+    #[clap(long, value_parser)]
+    forks: Option<NonZeroU32>,
+
+    /// Check the generated sudoers files with visudo on this machine before running anything.
+    #[clap(long)]
+    check_sudoers: bool,
+
+    /// Times to retry ansible-playbook if it fails to start or times out, with backoff.
+    #[clap(long, value_parser, default_value_t = 0)]
+    retries: u32,
+
+    /// Kill ansible-playbook if it runs for longer than this many seconds.
+    #[clap(long, value_parser)]
+    timeout: Option<u64>,
+
+    /// Reprint ansible's output with an sshman prefix and a count of the tasks started so far,
+    /// instead of passing it through with its colours.
+    #[clap(long)]
+    progress: bool,
+
+    /// Have ansible-playbook prompt for the password to become root with, e.g. for sudo.
+    #[clap(long)]
+    ask_become_pass: bool,
+
+    /// File for ansible-playbook to read the vault password from.
+    #[clap(long, value_parser)]
+    vault_password_file: Option<String>,
+
+    /// YAML or JSON file of variables to pass to every play, e.g. proxy settings.
+    #[clap(long, value_parser)]
+    extra_vars_file: Option<String>,
+
+    /// Variable to pass to every play as KEY=VALUE. May be given more than once.
+    /// These take precedence over the variables in --extra-vars-file.
+    #[clap(long, value_parser)]
+    extra_var: Vec<ExtraVar>,
+
+    /// Run the playbook on this host with ansible-pull instead of pushing it with ansible-playbook,
+    /// checking out the repository at this URL, e.g. for hosts behind NAT.
+    /// Only used by run, bootstrap and rollback.
+    #[clap(long, value_parser)]
+    pull: Option<String>,
+
+    /// Kill ansible-inventory if it runs for longer than this many seconds, or 0 to wait forever.
+    #[clap(long, value_parser, default_value_t = 60)]
+    inventory_timeout: u64,
+
+    /// Combine the plays for each host pattern into one, so each host is connected to fewer times.
+    #[clap(long)]
+    coalesce: bool,
+
+    /// Remove sshman- groups and groups named after configured users from hosts they are no longer
+    /// configured for. Groups that are an account's primary group are kept.
+    #[clap(long)]
+    prune_groups: bool,
+
+    /// YAML or JSON file mapping groups in an identity source, e.g. LDAP, to the access
+    /// statements their members get, in addition to the access in the config.
+    #[clap(long, value_parser, requires = "group_members")]
+    group_access: Option<String>,
+
+    /// YAML or JSON file mapping users to the identity groups they are members of.
+    #[clap(long, value_parser, requires = "group_access")]
+    group_members: Option<String>,
+
+    /// Earlier snapshot of the config. Run and write then only manage the users that were added
+    /// or changed since, and remove the keys of users that were removed.
+    #[clap(long, value_parser, conflicts_with = "prune_groups")]
+    since: Option<String>,
+
+    /// Don't print a summary of the users, host patterns and keys after a run.
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Log more detail to stderr; -v for commands run, -vv for inventory and play details.
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print what would be done instead of running ansible or any other command.
+    /// Playbooks are still written to a temp file, which is kept so it can be inspected.
+    #[clap(long)]
+    dry_run: bool,
 
     /// What to do with the generated playbook.
     #[clap(subcommand)]
@@ -36,39 +186,615 @@ enum Action {
     },
     /// Writes the playbook to a file.
     Write {
-        /// Path to write the playbook to.
+        /// Path to write the playbook to, or a directory with --split.
         #[clap(value_parser)]
         path: String,
+        /// Write each play to its own file in the directory, with an index.yml that imports them.
+        #[clap(long)]
+        split: bool,
     },
     /// Reports on public keys in accounts that aren't configured with sshman.
     Validate {
+        /// Also write a report of the results, as FORMAT:PATH. Supported formats: junit.
+        #[clap(long, value_parser)]
+        report: Option<Report>,
+
+        /// Have the playbook write the extra and missing keys on each host to this path as JSON,
+        /// then print them once it finishes.
+        #[clap(long, value_parser)]
+        artifact: Option<PathBuf>,
+
+        /// Format to print the drift from the artifact in.
+        #[clap(long, value_enum, default_value_t, requires = "artifact")]
+        format: DisplayFormat,
+
+        /// Remove the extra keys found from the hosts they were found on, after confirming.
+        /// Missing keys are left for run to add.
+        #[clap(long, value_parser)]
+        fix: bool,
+
+        /// Remove the extra keys without asking for confirmation.
+        #[clap(long, value_parser, requires = "fix")]
+        yes: bool,
+
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+    /// Checks the playbook with ansible-playbook's syntax check, then runs it in check mode
+    /// against a throwaway inventory, e.g. of test containers, printing ansible's output on failure.
+    Test {
+        /// Inventory of the hosts to run the playbook against in check mode.
+        #[clap(value_parser)]
+        inventory: String,
+
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+    /// Shows the accounts and keys a run would change on each host, without changing anything.
+    Diff {
         /// Extra arguments to pass to ansible-playbook.
         #[clap(last = true)]
         playbook_args: Vec<String>,
     },
     /// Displays a report mapping users to their individual host access.
-    Display,
+    Display {
+        /// Format to display the report in.
+        #[clap(long, value_enum, default_value_t)]
+        format: DisplayFormat,
+
+        /// Number of host patterns to resolve in parallel.
+        #[clap(long, value_parser, default_value = "4")]
+        jobs: NonZeroUsize,
+    },
+    /// Checks the config for logic errors, exiting with an error if any are found.
+    Lint,
+    /// Displays the users and roles that have access to one host but not the other.
+    CompareHosts {
+        /// Name of the first host.
+        #[clap(value_parser)]
+        first: String,
+        /// Name of the second host.
+        #[clap(value_parser)]
+        second: String,
+    },
+    /// Prints a Graphviz DOT graph of the hosts each user can access, with edges coloured by role.
+    /// Pipe it to e.g. `dot -Tpng -o access.png` to render it.
+    Graph,
+    /// Lists the hosts in the inventory that no access statement matches.
+    Uncovered {
+        /// Exit with an error if any hosts are uncovered.
+        #[clap(long)]
+        strict: bool,
+    },
+    /// Prints the authorized_keys content for each user, and the host patterns it is used on.
+    Keys {
+        /// Only print the keys for this user.
+        #[clap(value_parser)]
+        user: Option<String>,
+    },
+    /// Reads the authorized keys on hosts and prints a draft config granting each user access.
+    /// The draft should be reviewed, as every user found is given the same role.
+    Import {
+        /// Host pattern to read keys from.
+        #[clap(long, value_parser, default_value = "all")]
+        hosts: String,
+        /// Role to give each user: blocked, disabled, sudoer, nopass, superuser or absent.
+        #[clap(long, value_parser, default_value = "sudoer")]
+        role: Role,
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+    /// Prints a JSON Schema of the config format, e.g. for validating configs in an editor.
+    Schema,
+    /// Removes a public key from every account on every host, e.g. after the private key leaks.
+    /// Accounts that sshman doesn't manage are included, and the key is matched ignoring
+    /// its options and comment.
+    RevokeKey {
+        /// The public key to remove.
+        #[clap(value_parser)]
+        key: String,
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+    /// Generates and runs a playbook that only creates the groups and sudoers files.
+    Bootstrap {
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+    /// Generates and runs a playbook that removes the keys, groups and sudoers files sshman manages.
+    Rollback {
+        /// Also delete the accounts of managed users. Their home directories are kept.
+        #[clap(long)]
+        purge_users: bool,
+
+        /// Extra arguments to pass to ansible-playbook.
+        #[clap(last = true)]
+        playbook_args: Vec<String>,
+    },
+}
+
+/// A format for the Display report.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+enum DisplayFormat {
+    #[default]
+    Text,
+    Json,
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let conf_content = fs::read_to_string(&args.config).expect("Failed to read config file.");
-    let conf: SSHConfig =
-        serde_yaml::from_str(&conf_content).expect("Failed to parse config file.");
-
-    match args.command {
-        Action::Run { playbook_args } => run_plays(&conf.create_accounts(), &playbook_args),
-        Action::Write { path } => {
-            fs::write(
-                path,
-                serde_yaml::to_string(&conf.create_accounts())
-                    .expect("Failed to serialize playbook."),
+    env_logger::Builder::new()
+        .filter_level(match args.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+        .init();
+    if args.config.is_empty()
+        && !matches!(
+            args.command,
+            Action::Import { .. } | Action::Schema | Action::RevokeKey { .. }
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the --config argument is required for this command",
+            )
+            .exit();
+    }
+    if args.pull.is_some()
+        && matches!(
+            args.command,
+            Action::Validate { .. }
+                | Action::Diff { .. }
+                | Action::Import { .. }
+                | Action::Test { .. }
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--pull can't be used with validate, diff, import or test, which read ansible's output",
             )
-            .expect("Failed to write playbook.");
+            .exit();
+    }
+    let mut conf = read_configs(&args.config)?;
+    let identity = match (&args.group_access, &args.group_members) {
+        (Some(access), Some(members)) => Some((
+            identity::read::<GroupAccess>(access)?,
+            identity::read::<Memberships>(members)?,
+        )),
+        _ => None,
+    };
+    if let Some((groups, memberships)) = &identity {
+        for name in identity::derive_access(&mut conf.users, groups, memberships) {
+            eprintln!("Warning: group member {name} isn't a user in the config, so is left out");
+        }
+    }
+
+    let mut check_opts = CheckOptions {
+        revoked: match &args.krl {
+            Some(path) => keys::read_krl(path)?,
+            None => HashSet::new(),
+        },
+        inventory: args.inventory.as_deref().map(Inventory::read).transpose()?,
+        allow_empty_patterns: args.allow_empty_patterns,
+        allow_multiple_superusers: args.allow_multiple_superusers,
+    };
+    let inventory_timeout = Some(args.inventory_timeout)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    // A typo in a group name matches no hosts, which ansible only warns about.
+    let runs_playbook = matches!(
+        args.command,
+        Action::Run { .. } | Action::Validate { .. } | Action::Diff { .. }
+    );
+    // With --pull the inventory is in the repository, which ansible-pull hasn't checked out yet.
+    if check_opts.inventory.is_none() && runs_playbook && !args.dry_run && args.pull.is_none() {
+        check_opts.inventory = Some(subprocess::full_inventory(inventory_timeout)?);
+    }
+    conf.validate_keys()?;
+    conf.check(&check_opts)?;
+    // Lint reports these itself.
+    if !matches!(args.command, Action::Lint) {
+        for warning in conf.warnings() {
+            eprintln!("Warning: {warning}");
+        }
+    }
+
+    if args.check_sudoers {
+        for (group, content) in conf.sudoers_files() {
+            if args.dry_run {
+                println!("Would check the sudoers file for {group} with visudo.");
+            } else {
+                subprocess::validate_sudoers(group, &content)?;
+            }
+        }
+    }
+
+    let play_opts = PlayOptions {
+        run_once: args.run_once,
+        become_method: args.become_method,
+        connection: args.connection.clone(),
+        limit: args.limit.clone(),
+        coalesce: args.coalesce,
+        prune_groups: args.prune_groups,
+    };
+    let mut opts = RunOptions {
+        transform: args.transform,
+        dry_run: args.dry_run,
+        check: false,
+        limit: args.limit.as_ref().map(ToString::to_string),
+        connection: Connection {
+            user: args.remote_user,
+            port: args.port,
+            private_key_file: args.private_key,
+        }
+        .or(conf.connection.clone().unwrap_or_default()),
+        connection_type: args.connection,
+        forks: args.forks.or(conf.forks),
+        retries: args.retries,
+        timeout: args.timeout.map(Duration::from_secs),
+        ask_become_pass: args.ask_become_pass,
+        progress: args.progress,
+        vault_password_file: args.vault_password_file,
+        extra_vars_file: args.extra_vars_file,
+        extra_vars: args.extra_var,
+        pull: args.pull,
+    };
+
+    // An inventory file can always be used, but ansible-inventory isn't run in a dry run.
+    let resolves_hosts = matches!(
+        args.command,
+        Action::Display { .. }
+            | Action::CompareHosts { .. }
+            | Action::Graph
+            | Action::Uncovered { .. }
+    );
+    let full_inventory = match &check_opts.inventory {
+        None if resolves_hosts && !opts.dry_run => {
+            Some(subprocess::full_inventory(inventory_timeout)?)
+        }
+        _ => None,
+    };
+    let inventory = check_opts.inventory.as_ref().or(full_inventory.as_ref());
+
+    let changes = match &args.since {
+        Some(path) => {
+            let mut old = read_configs(slice::from_ref(path))?;
+            // Otherwise every user with group access would look changed.
+            if let Some((groups, memberships)) = &identity {
+                identity::derive_access(&mut old.users, groups, memberships);
+            }
+            let changes = conf.diff(&old);
+            info!(
+                "{} users added, {} changed and {} removed since {path}",
+                changes.added.len(),
+                changes.changed.len(),
+                changes.removed.len()
+            );
+            Some(changes)
+        }
+        None => None,
+    };
+    let changed_conf = changes.as_ref().map(|changes| conf.only_changed(changes));
+    // The plays that run and write generate, for every user or only the changed users.
+    let account_plays = || {
+        let mut plays = changed_conf
+            .as_ref()
+            .unwrap_or(&conf)
+            .create_accounts(&play_opts);
+        if let Some(changes) = &changes {
+            plays.extend(changes.revoke_removed(&play_opts));
+        }
+        plays
+    };
+
+    let status = match args.command {
+        Action::Run { playbook_args } => {
+            let status = run_plays(&account_plays(), &playbook_args, &opts)?;
+            if !args.quiet && !opts.dry_run {
+                println!("{}", changed_conf.as_ref().unwrap_or(&conf).summary());
+            }
+            status
+        }
+        Action::Test {
+            inventory,
+            playbook_args,
+        } => {
+            subprocess::test_plays(&account_plays(), &inventory, &playbook_args, &opts)?;
+            if !opts.dry_run {
+                println!(
+                    "Playbook passed the syntax check and a check mode run against {inventory}."
+                );
+            }
+            ExitStatus::default()
         }
-        Action::Display => conf.display(),
-        Action::Validate { playbook_args } => {
-            run_plays(&AnsiblePlay::validate(&conf), &playbook_args)
+        Action::Write { path, split } => {
+            let plays = account_plays();
+            if split || Path::new(&path).is_dir() {
+                let index = subprocess::write_split_playbook(&plays, Path::new(&path), &opts)?;
+                println!(
+                    "Wrote {} plays imported by {}",
+                    plays.len(),
+                    index.display()
+                );
+            } else {
+                fs::write(&path, opts.serialize(&plays)?)
+                    .with_context(|| format!("Failed to write playbook to {path}"))?;
+            }
+            ExitStatus::default()
+        }
+        Action::Diff { playbook_args } => {
+            opts.check = true;
+            let plays = AnsiblePlay::diff(&conf, &play_opts);
+
+            if opts.dry_run {
+                run_plays(&plays, &playbook_args, &opts)?
+            } else {
+                let (output, status) = run_plays_json(&plays, &playbook_args, &opts)?;
+                print_diff(&report::parse_diff_output(&output));
+                status
+            }
+        }
+        Action::Display { format, jobs } => {
+            match format {
+                DisplayFormat::Text => conf.display(inventory, jobs),
+                DisplayFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&conf.display_json(inventory, jobs)?)?
+                ),
+            }
+            ExitStatus::default()
+        }
+        Action::Lint => {
+            let findings = conf.lint(check_opts.inventory.as_ref());
+            for finding in &findings {
+                println!("{finding}");
+            }
+
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                exit(1);
+            }
+            ExitStatus::default()
+        }
+        Action::CompareHosts { first, second } => {
+            if let Some(inventory) = inventory {
+                conf.compare_hosts(&first, &second, inventory)?;
+            } else {
+                println!("Would resolve host patterns with ansible-inventory and compare {first} to {second}.");
+            }
+            ExitStatus::default()
+        }
+        Action::Uncovered { strict } => {
+            if let Some(inventory) = inventory {
+                let uncovered = conf.uncovered_hosts(inventory);
+                for host in uncovered.iter().sorted() {
+                    println!("{host}");
+                }
+                if strict && !uncovered.is_empty() {
+                    eprintln!(
+                        "{} hosts aren't matched by any access statement.",
+                        uncovered.len()
+                    );
+                    exit(1);
+                }
+            } else {
+                println!(
+                    "Would resolve host patterns with ansible-inventory and list uncovered hosts."
+                );
+            }
+            ExitStatus::default()
+        }
+        Action::Graph => {
+            if let Some(inventory) = inventory {
+                print!(
+                    "{}",
+                    graph::to_dot(&conf, &conf.resolve_patterns(inventory)?)
+                );
+            } else {
+                println!("Would resolve host patterns with ansible-inventory and print a graph.");
+            }
+            ExitStatus::default()
+        }
+        Action::Validate {
+            report,
+            artifact,
+            format,
+            fix,
+            yes,
+            playbook_args,
+        } => {
+            // The artifact is written by ansible, which may not run in this directory.
+            let artifact = artifact.map(std::path::absolute).transpose()?;
+            let plays = AnsiblePlay::validate(&conf, &play_opts, artifact.as_deref());
+            // The drift found, if it was read from ansible's output or the artifact.
+            let mut drift = None;
+
+            let mut status = match report {
+                // Fixing needs the drift, which is otherwise only printed by ansible.
+                None if fix && artifact.is_none() && !opts.dry_run => {
+                    let (output, status) = run_plays_json(&plays, &playbook_args, &opts)?;
+                    let (_, reports) = report::parse_validate_output(&output);
+                    print_validation(&reports);
+                    drift = Some(reports);
+                    status
+                }
+                None => run_plays(&plays, &playbook_args, &opts)?,
+                Some(Report::Junit(path)) if opts.dry_run => {
+                    let status = run_plays(&plays, &playbook_args, &opts)?;
+                    println!("Would write junit report to: {}", path.display());
+                    status
+                }
+                Some(Report::Junit(path)) => {
+                    let (output, status) = run_plays_json(&plays, &playbook_args, &opts)?;
+                    let (hosts, reports) = report::parse_validate_output(&output);
+
+                    print_validation(&reports);
+                    println!(
+                        "{} of {} hosts have key drift.",
+                        reports.iter().map(|report| &report.host).unique().count(),
+                        hosts.len()
+                    );
+
+                    fs::write(&path, report::junit(&hosts, &reports))
+                        .with_context(|| format!("Failed to write report to {}", path.display()))?;
+                    drift = Some(reports);
+                    status
+                }
+            };
+
+            match artifact {
+                Some(path) if opts.dry_run => {
+                    println!("Would read the key drift from: {}", path.display());
+                }
+                Some(path) => {
+                    let reports = subprocess::read_validation_artifact(&path)?;
+                    match format {
+                        DisplayFormat::Text => print_validation(&reports),
+                        DisplayFormat::Json => {
+                            println!("{}", serde_json::to_string_pretty(&reports)?);
+                        }
+                    }
+                    drift = Some(reports);
+                }
+                None => {}
+            }
+
+            if fix && opts.dry_run {
+                println!("Would remove the extra keys found, after confirming.");
+            } else if fix {
+                let drift = drift.unwrap_or_default();
+                let plays = AnsiblePlay::remove_extra_keys(&conf, &drift, &play_opts);
+                let keys = drift
+                    .iter()
+                    .map(|report| report.extra_keys.len())
+                    .sum::<usize>();
+                let hosts = drift
+                    .iter()
+                    .filter(|report| !report.extra_keys.is_empty())
+                    .map(|report| &report.host)
+                    .unique()
+                    .count();
+
+                if plays.is_empty() {
+                    println!("No extra keys to remove.");
+                } else if yes || confirm(&format!("Remove {keys} extra keys from {hosts} hosts?"))?
+                {
+                    let fix_status = run_plays(&plays, &playbook_args, &opts)?;
+                    // Otherwise exit with the validate status, as the keys did drift.
+                    if !fix_status.success() {
+                        status = fix_status;
+                    }
+                } else {
+                    println!("Not removing any keys.");
+                }
+            }
+            status
+        }
+        Action::Import {
+            hosts,
+            role,
+            playbook_args,
+        } => {
+            let plays = AnsiblePlay::import(&hosts, &play_opts);
+            if opts.dry_run {
+                run_plays(&plays, &playbook_args, &opts)?
+            } else {
+                let (output, status) = run_plays_json(&plays, &playbook_args, &opts)?;
+                let found = report::parse_import_output(&output);
+                print!(
+                    "{}",
+                    serde_yaml::to_string(&SSHConfig::from_found_keys(&found, role))?
+                );
+                status
+            }
+        }
+        Action::Keys { user } => {
+            print!("{}", conf.keys_report(user.as_deref())?);
+            ExitStatus::default()
+        }
+        Action::RevokeKey { key, playbook_args } => run_plays(
+            &AnsiblePlay::revoke_key(&key, &play_opts)?,
+            &playbook_args,
+            &opts,
+        )?,
+        Action::Schema => {
+            println!("{}", serde_json::to_string_pretty(&schema_for!(SSHConfig))?);
+            ExitStatus::default()
+        }
+        Action::Bootstrap { playbook_args } => {
+            run_plays(&conf.bootstrap(&play_opts), &playbook_args, &opts)?
+        }
+        Action::Rollback {
+            purge_users,
+            playbook_args,
+        } => run_plays(
+            &conf.rollback_plays(purge_users, &play_opts),
+            &playbook_args,
+            &opts,
+        )?,
+    };
+
+    // Exit with the same code as ansible so failures, e.g. key drift found by Validate, fail CI.
+    if !status.success() {
+        exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
+
+/// Asks a yes or no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Prints the extra and missing keys for each user on each host.
+fn print_validation(reports: &[ValidationReport]) {
+    for report in reports {
+        println!("# {} on {}", report.user, report.host);
+        for key in &report.extra_keys {
+            println!("  + {key}");
+        }
+        for key in &report.missing_keys {
+            println!("  - {key}");
+        }
+    }
+}
+
+/// Prints the changes that would be made for each user on each host.
+fn print_diff(diffs: &[UserDiff]) {
+    for (host, host_diffs) in &diffs.iter().chunk_by(|diff| &diff.host) {
+        println!("# {host}");
+        for diff in host_diffs {
+            println!("## {}", diff.user);
+            if diff.account_changed {
+                println!("  account would be created or modified");
+            }
+            for (label, prefix, keys) in [
+                ("would add", '+', &diff.add),
+                ("would remove", '-', &diff.remove),
+                ("unchanged", '=', &diff.unchanged),
+            ] {
+                if !keys.is_empty() {
+                    println!("  {label}:");
+                    for key in keys {
+                        println!("    {prefix} {key}");
+                    }
+                }
+            }
         }
+        println!();
     }
 }
@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use itertools::Itertools;
 use serde::ser::SerializeMap;
 use serde::Serialize;
 use serde_yaml::Value;
@@ -15,8 +16,28 @@ pub struct AnsiblePlay<'a> {
     pub gather_facts: bool,
     /// Whether to execute the whole play as root.
     pub r#become: bool,
+    /// How to become root, e.g. `doas`. Ansible's default is used if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub become_method: Option<String>,
+    /// User to become, instead of ansible's default of root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub become_user: Option<String>,
+    /// Connection plugin to manage the hosts with, e.g. `local` or `docker`,
+    /// instead of ansible's default of ssh.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection: Option<String>,
     /// The tasks in this play.
     pub tasks: Vec<AnsibleTask<'a>>,
+    /// Tasks run at the end of the play if notified by a task that changed something.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub handlers: Vec<AnsibleTask<'a>>,
+}
+
+/// An entry in a playbook that imports the plays from another playbook file.
+#[derive(Debug, Serialize)]
+pub struct PlaybookImport {
+    /// Path to the playbook to import, relative to the importing playbook.
+    pub import_playbook: String,
 }
 
 #[derive(Debug)]
@@ -27,6 +48,46 @@ pub struct AnsibleTask<'a> {
     pub params: HashMap<&'static str, Value>,
 }
 
+impl AnsibleTask<'_> {
+    /// Notifies the handler with this name when the task changes something.
+    pub fn notify(mut self, handler: &'static str) -> Self {
+        match self.params.get_mut("notify") {
+            Some(Value::Sequence(handlers)) => handlers.push(handler.into()),
+            Some(existing) => *existing = Value::from(vec![existing.clone(), handler.into()]),
+            None => {
+                self.params.insert("notify", handler.into());
+            }
+        }
+        self
+    }
+
+    /// Only runs the task when the jinja expression is true, as well as any existing condition.
+    pub fn when(mut self, condition: &str) -> Self {
+        match self.params.get_mut("when") {
+            Some(Value::Sequence(conditions)) => conditions.push(condition.into()),
+            Some(existing) => *existing = Value::from(vec![existing.clone(), condition.into()]),
+            None => {
+                self.params.insert("when", condition.into());
+            }
+        }
+        self
+    }
+
+    /// Adds a tag to the task, so it can be selected with `--tags` or `--skip-tags`.
+    pub fn tagged(mut self, tag: &'static str) -> Self {
+        match self
+            .params
+            .entry("tags")
+            .or_insert_with(|| Value::Sequence(vec![]))
+        {
+            Value::Sequence(tags) => tags.push(tag.into()),
+            existing => *existing = Value::from(vec![existing.clone(), tag.into()]),
+        }
+        self
+    }
+}
+
+/// Parameters are written sorted by name, so the same plays always serialize the same way.
 impl Serialize for AnsibleTask<'_> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -34,9 +95,12 @@ impl Serialize for AnsibleTask<'_> {
     {
         let mut map = serializer.serialize_map(Some(2 + self.params.len()))?;
         map.serialize_entry("name", &self.name)?;
-        map.serialize_entry(&self.module.name, &self.module.params)?;
+        map.serialize_entry(
+            &self.module.name,
+            &self.module.params.iter().collect::<BTreeMap<_, _>>(),
+        )?;
 
-        for (key, value) in &self.params {
+        for (key, value) in self.params.iter().sorted_by_key(|(key, _)| *key) {
             map.serialize_entry(key, value)?;
         }
 
@@ -35,40 +35,90 @@ impl<'a> AnsibleModule<'a> {
         }
     }
 
-    /// Creates a sudo file for the group, allowing them to use sudo, with the rootpw flag set.
+    /// Ansible module for adding or removing host keys in a known_hosts file.
+    pub fn known_hosts(params: HashMap<&'static str, String>) -> Self {
+        Self {
+            name: "ansible.builtin.known_hosts",
+            params: params
+                .into_iter()
+                .map(|(k, v)| (k, Value::String(v)))
+                .collect(),
+        }
+    }
+
+    /// Creates a sudo file for the role's group with the given content.
     /// Validates with visudo.
-    pub fn sudo_file(role: Role) -> Self {
+    pub fn sudo_file(role: Role, content: String) -> Self {
         let group = role.group();
-        match role {
-            Role::Nopass => Self {
-                name: "ansible.builtin.copy",
-                params: HashMap::from([
-                    (
-                        "content",
-                        Value::String(format!(
-                            "%{group} ALL=(ALL) NOPASSWD: ALL\nDefaults:%{group} !requiretty\n"
-                        )),
-                    ),
-                    ("dest", format!("/etc/sudoers.d/{group}").into()),
-                    ("mode", "440".into()),
-                    ("validate", "visudo -cf %s".into()),
-                ]),
-            },
-            Role::Sudoer => Self {
-                name: "ansible.builtin.copy",
-                params: HashMap::from([
-                    (
-                        "content",
-                        Value::String(format!(
-                            "%{group} ALL=(ALL) ALL\nDefaults:%{group} rootpw\n"
-                        )),
-                    ),
-                    ("dest", format!("/etc/sudoers.d/{group}").into()),
-                    ("mode", "440".into()),
-                    ("validate", "visudo -cf %s".into()),
-                ]),
-            },
-            other => panic!("Creating sudo file for role {other}"),
+        Self {
+            name: "ansible.builtin.copy",
+            params: HashMap::from([
+                ("content", Value::String(content)),
+                ("dest", format!("/etc/sudoers.d/{group}").into()),
+                ("mode", "440".into()),
+                ("validate", "visudo -cf %s".into()),
+            ]),
+        }
+    }
+
+    /// Writes the content to a file.
+    pub fn copy(content: String, dest: &str) -> Self {
+        Self {
+            name: "ansible.builtin.copy",
+            params: HashMap::from([("content", Value::String(content)), ("dest", dest.into())]),
+        }
+    }
+
+    /// Ensures a line is in a file.
+    pub fn lineinfile(params: HashMap<&'static str, Value>) -> Self {
+        Self {
+            name: "ansible.builtin.lineinfile",
+            params,
+        }
+    }
+
+    /// Sets the line in sshd_config matching the regex, or removes matching lines if there is
+    /// no line. The edited config is checked with `sshd -t` before it replaces the original.
+    /// Tasks using this should notify a handler to reload sshd.
+    pub fn sshd_config(regexp: &str, line: Option<&str>) -> Self {
+        let mut params = HashMap::from([
+            ("path", "/etc/ssh/sshd_config".into()),
+            ("regexp", regexp.into()),
+            ("validate", "sshd -t -f %s".into()),
+        ]);
+        match line {
+            Some(line) => params.insert("line", line.into()),
+            None => params.insert("state", "absent".into()),
+        };
+
+        Self::lineinfile(params)
+    }
+
+    /// Maps a login to an SELinux user, like `semanage login`.
+    pub fn selinux_login(login: &str, seuser: &str) -> Self {
+        Self {
+            name: "community.general.selogin",
+            params: HashMap::from([
+                ("login", login.into()),
+                ("seuser", seuser.into()),
+                ("state", "present".into()),
+            ]),
+        }
+    }
+
+    /// Sets the state of a service, e.g. to reload it.
+    pub fn service(name: &str, state: &str) -> Self {
+        Self {
+            name: "ansible.builtin.service",
+            params: HashMap::from([("name", name.into()), ("state", state.into())]),
+        }
+    }
+
+    /// Sets the state of a file, e.g. to remove it.
+    pub fn file(path: &str, state: &str) -> Self {
+        Self {
+            name: "ansible.builtin.file",
+            params: HashMap::from([("path", path.into()), ("state", state.into())]),
         }
     }
 
@@ -96,6 +146,15 @@ impl<'a> AnsibleModule<'a> {
         }
     }
 
+    /// Fails the host with a message.
+    pub fn fail(msg: &str) -> Self {
+        Self {
+            name: "ansible.builtin.fail",
+            params: HashMap::from([("msg", msg.into())]),
+        }
+    }
+
+    /// Prints a message, which may be a template.
     pub fn debug(msg: &str) -> Self {
         Self {
             name: "ansible.builtin.debug",
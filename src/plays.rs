@@ -1,23 +1,164 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt::Display, path::Path, str::FromStr};
 
 use itertools::Itertools;
 use serde_yaml::Value;
 
 use crate::{
-    config::{Role, SSHConfig, SSHUser},
+    config::{AccessStmt, KeyState, Role, SSHConfig, SSHUser},
+    error::{InvalidKeyError, InvalidLimitError},
+    keys,
     model::{AnsibleModule, AnsiblePlay, AnsibleTask},
+    report::ValidationReport,
 };
 
+/// Name of the validate task that reports the extra keys on each host.
+pub const EXTRA_KEYS_TASK: &str = "Print extra users";
+
+/// Name of the validate task that reports the configured keys missing from each host.
+pub const MISSING_KEYS_TASK: &str = "Print missing keys";
+
+/// Name of the diff task that reports the desired and actual keys on each host.
+pub const KEY_FACTS_TASK: &str = "Report pubkey facts";
+
+/// Name of the import task that reports the keys found on each host.
+pub const IMPORT_KEYS_TASK: &str = "Report actual pubkeys";
+
+/// Name of the fact holding the sudoers content for each role group when rendered once.
+const SUDOERS_FACT: &str = "sshman_sudoers";
+
+/// File of CA keys trusted to sign user certificates.
+const TRUSTED_CA_KEYS: &str = "/etc/ssh/sshman_trusted_user_ca_keys";
+
+/// System-wide known_hosts file that ssh clients on the hosts read.
+const SSH_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+
+/// Name of the handler that reloads sshd after its config changes.
+const RELOAD_SSHD_HANDLER: &str = "Reload sshd.";
+
+/// Options controlling how plays are generated.
+#[derive(Debug, Default, Clone)]
+pub struct PlayOptions {
+    /// Render the sudoers file content once per play instead of once per host.
+    pub run_once: bool,
+    /// How plays should become root, e.g. `doas` or `su`.
+    pub become_method: Option<String>,
+    /// Connection plugin every play should use, e.g. `local` to test against this machine.
+    pub connection: Option<String>,
+    /// Restricts every play to the hosts matching this pattern.
+    pub limit: Option<HostLimit>,
+    /// Combine the account plays for the same hosts into one play, to connect to each host less.
+    pub coalesce: bool,
+    /// Remove groups sshman created on hosts that are no longer in the config for those hosts.
+    pub prune_groups: bool,
+}
+
+impl PlayOptions {
+    /// Sets the options that apply to whole plays on each of the plays.
+    /// Must only be called once for each play.
+    pub fn apply(&self, plays: &mut [AnsiblePlay]) {
+        for play in plays {
+            play.become_method.clone_from(&self.become_method);
+            play.connection.clone_from(&self.connection);
+            if let Some(limit) = &self.limit {
+                play.hosts = limit.restrict(&play.hosts);
+            }
+        }
+    }
+}
+
+/// A host pattern to restrict plays to.
+/// It may only have one plain term, as a union can't be intersected into another pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostLimit(String);
+
+impl HostLimit {
+    /// Returns a pattern matching the hosts that match both the pattern and this limit.
+    /// Ansible applies intersections and exclusions after the union of the plain terms,
+    /// so they can be appended to any pattern.
+    pub fn restrict(&self, hosts: &str) -> String {
+        let terms = self.0.split([':', ',']).map(|term| {
+            if term.starts_with(['&', '!']) {
+                term.to_string()
+            } else {
+                format!("&{term}")
+            }
+        });
+        std::iter::once(hosts.to_string()).chain(terms).join(":")
+    }
+}
+
+impl FromStr for HostLimit {
+    type Err = InvalidLimitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let terms = s.split([':', ',']).map(str::trim).collect::<Vec<_>>();
+        let plain = terms
+            .iter()
+            .filter(|term| !term.starts_with(['&', '!']))
+            .count();
+
+        if plain > 1
+            || terms
+                .iter()
+                .any(|term| term.trim_start_matches(['&', '!']).is_empty())
+        {
+            Err(InvalidLimitError {
+                limit: s.to_string(),
+            })
+        } else {
+            Ok(Self(terms.join(":")))
+        }
+    }
+}
+
+impl Display for HostLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl<'a> AnsiblePlay<'a> {
     /// Returns a play which will create necessary groups on all hosts.
-    pub fn create_groups<T: Iterator<Item = String>>(additional: T) -> Self {
+    ///
+    /// With `run_once` the sudoers content is rendered into a fact by a single `run_once` task,
+    /// which ansible shares with every host in the play.
+    /// The group and copy tasks are not safe to run once as their results must land on each host,
+    /// so they still run everywhere and only the templating work is saved.
+    pub fn create_groups<T: Iterator<Item = String>>(
+        additional: T,
+        sudoers_defaults: &[String],
+        opts: &PlayOptions,
+    ) -> Self {
         let additional_tasks = additional.unique().map(|grp| AnsibleTask {
             name: "Create additional group.",
             module: AnsibleModule::groups(HashMap::from([("name", grp)])),
             params: HashMap::new(),
         });
 
-        let all_tasks = additional_tasks.chain(vec![
+        let mut render_tasks = vec![];
+        let sudoers = |role: Role| {
+            if opts.run_once {
+                format!("{{{{ {SUDOERS_FACT}['{}'] }}}}", role.group())
+            } else {
+                role.sudoers(sudoers_defaults)
+            }
+        };
+        if opts.run_once {
+            render_tasks.push(AnsibleTask {
+                name: "Render sudoers content.",
+                module: AnsibleModule::set_facts(HashMap::from([(
+                    SUDOERS_FACT,
+                    [Role::Sudoer, Role::Nopass]
+                        .iter()
+                        .map(|role| (role.group().into(), role.sudoers(sudoers_defaults).into()))
+                        .collect::<serde_yaml::Mapping>()
+                        .into(),
+                )])),
+                params: HashMap::from([("run_once", true.into())]),
+            });
+        }
+
+        let all_tasks = additional_tasks.chain(render_tasks).chain(vec![
             AnsibleTask {
                 name: "Create sudoer group.",
                 module: AnsibleModule::groups(HashMap::from([(
@@ -28,7 +169,7 @@ impl<'a> AnsiblePlay<'a> {
             },
             AnsibleTask {
                 name: "Set sudo permissions for sudoers.",
-                module: AnsibleModule::sudo_file(Role::Sudoer),
+                module: AnsibleModule::sudo_file(Role::Sudoer, sudoers(Role::Sudoer)),
                 params: HashMap::new(),
             },
             AnsibleTask {
@@ -41,7 +182,7 @@ impl<'a> AnsiblePlay<'a> {
             },
             AnsibleTask {
                 name: "Set sudo permissions for nopasss.",
-                module: AnsibleModule::sudo_file(Role::Nopass),
+                module: AnsibleModule::sudo_file(Role::Nopass, sudoers(Role::Nopass)),
                 params: HashMap::new(),
             },
         ]);
@@ -51,70 +192,181 @@ impl<'a> AnsiblePlay<'a> {
             hosts: "all".to_string(),
             gather_facts: false,
             r#become: true,
-            tasks: all_tasks.collect(),
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks: all_tasks.map(|task| task.tagged("groups")).collect(),
         }
     }
 
     /// Creates the user if they do not already exist, and sets their group.
+    /// Absent users have their keys and account removed instead.
     pub fn create_user(user: &SSHUser) -> Vec<Self> {
         user.access
             .iter()
             .map(|stmt| {
-                let group_tasks =
-                    stmt.groups
-                        .iter()
-                        .chain(vec![&user.name])
-                        .map(|grp| AnsibleTask {
-                            name: "Create group user group.",
-                            module: AnsibleModule::groups(HashMap::from([("name", grp.into())])),
-                            params: HashMap::new(),
-                        });
+                let group_tasks = stmt
+                    .created_groups(&user.name)
+                    .into_iter()
+                    .filter(|_| stmt.role != Role::Absent)
+                    .map(|grp| AnsibleTask {
+                        name: "Create group user group.",
+                        module: AnsibleModule::groups(HashMap::from([("name", grp.into())])),
+                        params: HashMap::new(),
+                    });
+
+                // A primary group that isn't one of the extra groups must already exist,
+                // so the play fails before the account is created rather than part way through.
+                let primary_group_tasks = stmt
+                    .primary_group
+                    .iter()
+                    .filter(|grp| {
+                        matches!(stmt.role, Role::Sudoer | Role::Nopass)
+                            && !stmt.groups.contains(grp)
+                    })
+                    .map(|grp| AnsibleTask {
+                        name: "Check primary group exists.",
+                        module: AnsibleModule::getent(HashMap::from([
+                            ("database", "group".into()),
+                            ("key", grp.clone().into()),
+                        ])),
+                        params: HashMap::from([("changed_when", false.into())]),
+                    });
 
                 let user_tasks = match stmt.role {
                     Role::SuperUser => vec![AnsibleTask {
                         name: "Create root alias.",
-                        module: AnsibleModule::users(HashMap::from([
-                            ("name", user.name.clone().into()),
-                            (
-                                "groups",
-                                stmt.groups
-                                    .iter()
-                                    .chain(vec![&stmt.role.group().to_string()])
-                                    .map(|grp| Value::String(grp.to_string()))
-                                    .collect(),
-                            ),
-                            ("non_unique", "true".into()),
-                            ("uid", "0".into()),
-                            ("password", "*".into()),
-                        ])),
+                        module: AnsibleModule::users(account_params(
+                            user,
+                            stmt,
+                            [
+                                ("name", user.name.clone().into()),
+                                (
+                                    "groups",
+                                    stmt.groups
+                                        .iter()
+                                        .chain(vec![&stmt.role.group().to_string()])
+                                        .map(|grp| Value::String(grp.to_string()))
+                                        .collect(),
+                                ),
+                                ("non_unique", "true".into()),
+                                ("uid", "0".into()),
+                                ("password", "*".into()),
+                            ],
+                        )),
                         params: HashMap::new(),
                     }],
                     Role::Sudoer | Role::Nopass => vec![AnsibleTask {
                         name: "Create sudoer account.",
-                        module: AnsibleModule::users(HashMap::from([
-                            ("name", user.name.clone().into()),
-                            ("password", "*".into()),
-                            ("group", user.name.clone().into()),
-                            (
-                                "groups",
-                                stmt.groups
-                                    .iter()
-                                    .chain(vec![&stmt.role.group().to_string()])
-                                    .map(|grp| Value::String(grp.to_string()))
-                                    .collect(),
-                            ),
-                        ])),
+                        module: AnsibleModule::users(account_params(
+                            user,
+                            stmt,
+                            [
+                                ("name", user.name.clone().into()),
+                                ("password", "*".into()),
+                                (
+                                    "group",
+                                    stmt.primary_group
+                                        .as_ref()
+                                        .unwrap_or(&user.name)
+                                        .clone()
+                                        .into(),
+                                ),
+                                (
+                                    "groups",
+                                    stmt.groups
+                                        .iter()
+                                        .chain(vec![&stmt.role.group().to_string()])
+                                        .map(|grp| Value::String(grp.to_string()))
+                                        .collect(),
+                                ),
+                            ],
+                        )),
+                        params: HashMap::new(),
+                    }],
+                    Role::Disabled => vec![AnsibleTask {
+                        name: "Disable account.",
+                        module: AnsibleModule::users(account_params(
+                            user,
+                            stmt,
+                            [
+                                ("name", user.name.clone().into()),
+                                ("password_lock", true.into()),
+                                ("shell", "/usr/sbin/nologin".into()),
+                            ],
+                        )),
                         params: HashMap::new(),
                     }],
                     Role::Blocked => vec![],
+                    // The keys may be outside the home directory, which is kept by default.
+                    Role::Absent => vec![
+                        AnsibleTask {
+                            name: "Remove public keys.",
+                            module: AnsibleModule::keys(key_params(
+                                user,
+                                stmt,
+                                [
+                                    ("user", user.name.to_owned()),
+                                    (
+                                        "key",
+                                        user.stmt_keys(stmt).map(|pubkey| &pubkey.key).join("\n"),
+                                    ),
+                                    ("state", "absent".to_string()),
+                                ],
+                            )),
+                            params: HashMap::from([("ignore_errors", Value::Bool(true))]),
+                        },
+                        AnsibleTask {
+                            name: "Remove account.",
+                            module: AnsibleModule::users(
+                                [
+                                    ("name", user.name.clone().into()),
+                                    ("state", "absent".into()),
+                                    ("remove", stmt.remove_home.unwrap_or(false).into()),
+                                ]
+                                .into_iter()
+                                .chain(stmt.local.map(|local| ("local", local.into())))
+                                .collect(),
+                            ),
+                            params: HashMap::new(),
+                        },
+                    ],
                 };
+                // Ansible would otherwise print the password hash with the task's arguments.
+                let user_tasks = user_tasks.into_iter().map(|mut task| {
+                    if stmt.password_hash.is_some() {
+                        task.params.insert("no_log", true.into());
+                    }
+                    task
+                });
+
+                // Maps the login to its SELinux user, which the user module only does on creation.
+                let selinux_tasks = stmt
+                    .seuser
+                    .iter()
+                    .filter(|_| stmt.role.authorizes_keys())
+                    .map(|seuser| AnsibleTask {
+                        name: "Map SELinux user.",
+                        module: AnsibleModule::selinux_login(&user.name, seuser),
+                        params: HashMap::new(),
+                    });
 
                 Self {
                     name: format!("Create accounts for {}.", user.name),
                     hosts: stmt.hosts.clone(),
-                    gather_facts: false,
+                    gather_facts: stmt.needs_facts(),
                     r#become: true,
-                    tasks: group_tasks.chain(user_tasks).collect(),
+                    become_method: None,
+                    connection: None,
+                    become_user: stmt.become_user.clone(),
+                    handlers: vec![],
+                    tasks: group_tasks
+                        .chain(primary_group_tasks)
+                        .chain(user_tasks)
+                        .chain(selinux_tasks)
+                        .map(|task| stmt.condition(task).tagged("users"))
+                        .collect(),
                 }
             })
             .collect()
@@ -122,64 +374,480 @@ impl<'a> AnsiblePlay<'a> {
 
     /// Authorizes keys for a user.
     /// For blocked users this play can fail silently if they do not already have an account.
+    ///
+    /// Keys marked absent are left out of the exclusive key list and removed by their own task,
+    /// so they are removed even when the rest of the keys are not managed exclusively.
     pub fn authorize_keys(user: &SSHUser) -> Vec<Self> {
         user.access
             .iter()
+            // Absent users' keys are removed with their account.
+            .filter(|stmt| stmt.role != Role::Absent)
             .map(|stmt| Self {
                 name: format!("Authorize keys for {}.", &user.name),
                 hosts: stmt.hosts.clone(),
-                r#become: true,
-                gather_facts: false,
-                tasks: vec![AnsibleTask {
+                r#become: stmt.r#become.unwrap_or(true),
+                become_method: None,
+                connection: None,
+                become_user: stmt.become_user.clone(),
+                handlers: vec![],
+                gather_facts: stmt.needs_facts(),
+                tasks: std::iter::once(AnsibleTask {
                     name: "Authorize public key.",
-                    module: AnsibleModule::keys(HashMap::from([
-                        ("user", user.name.to_owned()),
-                        ("key", user.pubkeys.join("\n")),
-                        ("exclusive", "true".to_string()),
-                        (
-                            "state",
-                            if stmt.role == Role::Blocked {
-                                "absent".to_string()
-                            } else {
-                                "present".to_string()
-                            },
-                        ),
-                    ])),
+                    module: AnsibleModule::keys(key_params(
+                        user,
+                        stmt,
+                        [
+                            ("user", user.name.to_owned()),
+                            (
+                                "key",
+                                if stmt.role == Role::Blocked {
+                                    user.stmt_keys(stmt).map(|pubkey| &pubkey.key).join("\n")
+                                } else {
+                                    user.authorized_keys(stmt)
+                                },
+                            ),
+                            // Blocked users have all their configured keys removed either way.
+                            ("exclusive", stmt.exclusive.unwrap_or(true).to_string()),
+                            (
+                                "state",
+                                if stmt.role == Role::Blocked {
+                                    "absent".to_string()
+                                } else {
+                                    "present".to_string()
+                                },
+                            ),
+                        ],
+                    )),
                     params: if stmt.role == Role::Blocked {
                         HashMap::from([("ignore_errors", Value::Bool(true))])
                     } else {
                         HashMap::new()
                     },
-                }],
+                })
+                // Blocked users already have all their keys removed.
+                .chain(
+                    user.absent_keys(stmt)
+                        .filter(|_| stmt.role != Role::Blocked)
+                        .map(|key| AnsibleTask {
+                            name: "Remove public key.",
+                            module: AnsibleModule::keys(key_params(
+                                user,
+                                stmt,
+                                [
+                                    ("user", user.name.to_owned()),
+                                    ("key", key.to_string()),
+                                    ("state", "absent".to_string()),
+                                ],
+                            )),
+                            params: HashMap::new(),
+                        }),
+                )
+                .map(|task| stmt.condition(task).tagged("keys"))
+                .collect(),
+            })
+            .collect()
+    }
+
+    /// Trusts certificate authorities to sign certificates for logins to hosts in each access group.
+    /// The CA keys are added to a file of trusted keys which sshd is configured to read,
+    /// and sshd is reloaded if its config changes.
+    pub fn trust_cas(user: &SSHUser) -> Vec<Self> {
+        user.access
+            .iter()
+            .filter(|stmt| !stmt.trusted_cas.is_empty())
+            .map(|stmt| Self {
+                name: format!("Trust certificate authorities for {}.", user.name),
+                hosts: stmt.hosts.clone(),
+                gather_facts: false,
+                r#become: true,
+                become_method: None,
+                connection: None,
+                become_user: stmt.become_user.clone(),
+                tasks: stmt
+                    .trusted_cas
+                    .iter()
+                    .map(|ca| AnsibleTask {
+                        name: "Trust certificate authority.",
+                        module: AnsibleModule::lineinfile(HashMap::from([
+                            ("path", TRUSTED_CA_KEYS.into()),
+                            ("line", ca.as_str().into()),
+                            ("create", true.into()),
+                            ("mode", "644".into()),
+                        ])),
+                        params: HashMap::new(),
+                    })
+                    .chain([AnsibleTask {
+                        name: "Configure sshd to trust certificate authorities.",
+                        module: AnsibleModule::sshd_config(
+                            "^#?\\s*TrustedUserCAKeys\\s",
+                            Some(&format!("TrustedUserCAKeys {TRUSTED_CA_KEYS}")),
+                        ),
+                        params: HashMap::new(),
+                    }
+                    .notify(RELOAD_SSHD_HANDLER)])
+                    .map(|task| stmt.condition(task).tagged("cas"))
+                    .collect(),
+                handlers: vec![Self::reload_sshd_handler()],
+            })
+            .collect()
+    }
+
+    /// Adds the config's known host keys to the system-wide known_hosts file,
+    /// with one play for each host pattern that should trust them.
+    pub fn manage_known_hosts(conf: &SSHConfig) -> Vec<Self> {
+        conf.known_hosts
+            .iter()
+            .into_group_map_by(|known_host| known_host.hosts.as_deref().unwrap_or("all"))
+            .into_iter()
+            .sorted_by_key(|(hosts, _)| *hosts)
+            .map(|(hosts, known_hosts)| Self {
+                name: format!("Trust host keys on {hosts}."),
+                hosts: hosts.to_string(),
+                gather_facts: false,
+                r#become: true,
+                become_method: None,
+                connection: None,
+                become_user: None,
+                handlers: vec![],
+                tasks: known_hosts
+                    .into_iter()
+                    .map(|known_host| {
+                        AnsibleTask {
+                            name: "Trust host key.",
+                            module: AnsibleModule::known_hosts(HashMap::from([
+                                ("name", known_host.name.clone()),
+                                ("key", known_host.line()),
+                                ("path", SSH_KNOWN_HOSTS.to_string()),
+                                ("state", "present".to_string()),
+                            ])),
+                            params: HashMap::new(),
+                        }
+                        .tagged("known_hosts")
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Removes all of a user's keys on the hosts in each access group.
+    /// With `purge` the user's accounts are deleted too, but not their home directories.
+    /// The key tasks ignore errors, as the user may not have an account on every host.
+    pub fn remove_access(user: &SSHUser, purge: bool) -> Vec<Self> {
+        user.access
+            .iter()
+            .map(|stmt| {
+                let mut tasks = vec![AnsibleTask {
+                    name: "Remove public keys.",
+                    module: AnsibleModule::keys(key_params(
+                        user,
+                        stmt,
+                        [
+                            ("user", user.name.to_owned()),
+                            (
+                                "key",
+                                user.stmt_keys(stmt).map(|pubkey| &pubkey.key).join("\n"),
+                            ),
+                            ("state", "absent".to_string()),
+                        ],
+                    )),
+                    params: HashMap::from([("ignore_errors", Value::Bool(true))]),
+                }];
+
+                // Blocked users never had an account created.
+                if purge && stmt.role != Role::Blocked {
+                    tasks.push(AnsibleTask {
+                        name: "Remove account.",
+                        module: AnsibleModule::users(HashMap::from([
+                            ("name", user.name.clone().into()),
+                            ("state", "absent".into()),
+                        ])),
+                        params: HashMap::new(),
+                    });
+                }
+
+                Self {
+                    name: format!("Remove access for {}.", user.name),
+                    hosts: stmt.hosts.clone(),
+                    gather_facts: false,
+                    r#become: true,
+                    become_method: None,
+                    connection: None,
+                    become_user: stmt.become_user.clone(),
+                    handlers: vec![],
+                    tasks,
+                }
+            })
+            .collect()
+    }
+
+    /// Stops sshd trusting the certificate authorities trusted by [`AnsiblePlay::trust_cas`].
+    pub fn untrust_cas(user: &SSHUser) -> Vec<Self> {
+        user.access
+            .iter()
+            .filter(|stmt| !stmt.trusted_cas.is_empty())
+            .map(|stmt| Self {
+                name: format!("Untrust certificate authorities for {}.", user.name),
+                hosts: stmt.hosts.clone(),
+                gather_facts: false,
+                r#become: true,
+                become_method: None,
+                connection: None,
+                become_user: stmt.become_user.clone(),
+                tasks: vec![
+                    AnsibleTask {
+                        name: "Configure sshd to not trust certificate authorities.",
+                        module: AnsibleModule::sshd_config("^\\s*TrustedUserCAKeys\\s", None),
+                        params: HashMap::new(),
+                    }
+                    .notify(RELOAD_SSHD_HANDLER),
+                    AnsibleTask {
+                        name: "Remove trusted certificate authorities.",
+                        module: AnsibleModule::file(TRUSTED_CA_KEYS, "absent"),
+                        params: HashMap::new(),
+                    },
+                ],
+                handlers: vec![Self::reload_sshd_handler()],
             })
             .collect()
     }
 
+    /// Returns a play which removes the role groups and their sudoers files from all hosts.
+    /// Additional groups are left as they may have existed before sshman created them.
+    pub fn remove_groups() -> Self {
+        let tasks = [Role::Sudoer, Role::Nopass]
+            .into_iter()
+            .flat_map(|role| {
+                [
+                    AnsibleTask {
+                        name: "Remove sudo permissions.",
+                        module: AnsibleModule::file(
+                            &format!("/etc/sudoers.d/{}", role.group()),
+                            "absent",
+                        ),
+                        params: HashMap::new(),
+                    },
+                    AnsibleTask {
+                        name: "Remove role group.",
+                        module: AnsibleModule::groups(HashMap::from([
+                            ("name", role.group().to_string()),
+                            ("state", "absent".to_string()),
+                        ])),
+                        params: HashMap::new(),
+                    },
+                ]
+            })
+            .collect();
+
+        Self {
+            name: "Remove groups.".to_string(),
+            hosts: "all".to_string(),
+            gather_facts: false,
+            r#become: true,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks,
+        }
+    }
+
+    /// Removes the groups sshman would have created on each host that are no longer configured there.
+    /// Only `sshman-` groups and groups named after a configured user are candidates,
+    /// and groups that are still the primary group of an account are kept.
+    pub fn prune_groups(conf: &SSHConfig) -> Vec<Self> {
+        let mut plays = vec![];
+        for user in &conf.users {
+            for stmt in user.access.iter().filter(|stmt| stmt.role != Role::Absent) {
+                // Matches the groups created by create_user, which are created for every role
+                // except absent.
+                let groups = stmt.created_groups(&user.name);
+                plays.push(Self {
+                    name: format!(
+                        "Populate desired group facts for {} on hosts in {}",
+                        user.name, stmt.hosts
+                    ),
+                    hosts: stmt.hosts.clone(),
+                    gather_facts: false,
+                    r#become: false,
+                    become_method: None,
+                    connection: None,
+                    become_user: None,
+                    handlers: vec![],
+                    tasks: vec![AnsibleTask {
+                        name: "Populate desired group facts",
+                        module: AnsibleModule::set_facts(HashMap::from([(
+                            "desired_groups",
+                            format!(
+                                "{{{{ desired_groups | default([]) + {} }}}}",
+                                serde_json::to_string(&groups)
+                                    .expect("Failed to serialize groups.")
+                            )
+                            .into(),
+                        )])),
+                        params: HashMap::from([("changed_when", false.into())]),
+                    }
+                    .tagged("groups")],
+                });
+            }
+        }
+
+        let known_users = conf.users.iter().map(|user| &user.name).collect::<Vec<_>>();
+        let role_groups = [Role::Sudoer.group(), Role::Nopass.group()];
+        plays.push(Self {
+            name: "Prune groups.".to_string(),
+            hosts: "all".to_string(),
+            gather_facts: false,
+            r#become: true,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks: vec![
+                AnsibleTask {
+                    name: "Read contents of passwd db",
+                    module: AnsibleModule::getent(HashMap::from([("database", "passwd".into())])),
+                    params: HashMap::from([("changed_when", false.into())]),
+                },
+                AnsibleTask {
+                    name: "Read contents of group db",
+                    module: AnsibleModule::getent(HashMap::from([("database", "group".into())])),
+                    params: HashMap::from([("changed_when", false.into())]),
+                },
+                AnsibleTask {
+                    name: "Find stale groups",
+                    module: AnsibleModule::set_facts(HashMap::from([(
+                        "stale_groups",
+                        format!(
+                            "{{{{ (getent_group.keys() | select('match', '^sshman-') | list \
+                            + getent_group.keys() | select('in', {}) | list) \
+                            | reject('in', desired_groups | default([]) + {}) \
+                            | reject('in', getent_group | dict2items \
+                            | selectattr('value.1', 'in', getent_passwd.values() | map(attribute='2') | list) \
+                            | map(attribute='key') | list) | unique | list }}}}",
+                            serde_json::to_string(&known_users).expect("Failed to serialize users."),
+                            serde_json::to_string(&role_groups).expect("Failed to serialize groups."),
+                        )
+                        .into(),
+                    )])),
+                    params: HashMap::from([("changed_when", false.into())]),
+                },
+                AnsibleTask {
+                    name: "Remove stale group.",
+                    module: AnsibleModule::groups(HashMap::from([
+                        ("name", "{{ item }}".to_string()),
+                        ("state", "absent".to_string()),
+                    ])),
+                    params: HashMap::from([("loop", "{{ stale_groups }}".into())]),
+                },
+            ]
+            .into_iter()
+            .map(|task| task.tagged("groups"))
+            .collect(),
+        });
+
+        plays
+    }
+
+    /// Combines plays for the same host pattern and become user into the first such play.
+    /// Tasks keep their order relative to the other tasks for the same hosts,
+    /// so e.g. an account is still created before its keys are authorized.
+    ///
+    /// A play that doesn't escalate privileges is only combined with the last play for its hosts
+    /// if that play doesn't either, so it still runs without sudo and in order.
+    pub fn coalesce(plays: Vec<Self>) -> Vec<Self> {
+        let mut coalesced: Vec<Self> = vec![];
+        for play in plays {
+            let Some(existing) = coalesced
+                .iter_mut()
+                .rfind(|p| p.hosts == play.hosts && p.become_user == play.become_user)
+                .filter(|p| p.r#become == play.r#become)
+            else {
+                coalesced.push(play);
+                continue;
+            };
+
+            existing.name = format!("Manage access on {}.", existing.hosts);
+            existing.gather_facts |= play.gather_facts;
+            existing.tasks.extend(play.tasks);
+            for handler in play.handlers {
+                if !existing.handlers.iter().any(|h| h.name == handler.name) {
+                    existing.handlers.push(handler);
+                }
+            }
+        }
+        coalesced
+    }
+
+    /// Handler that reloads sshd, for plays with tasks that change its config.
+    /// Tasks notify it with [`AnsibleTask::notify`] and [`RELOAD_SSHD_HANDLER`].
+    fn reload_sshd_handler() -> AnsibleTask<'a> {
+        AnsibleTask {
+            name: RELOAD_SSHD_HANDLER,
+            module: AnsibleModule::service("sshd", "reloaded"),
+            params: HashMap::new(),
+        }
+    }
+
+    /// Sets the `desired_pubkeys` fact on each host to the keys each user should have there,
+    /// normalized to their type and data.
     pub fn set_desired_pubkey_facts(conf: &'a SSHConfig) -> Vec<Self> {
         let mut plays = vec![];
         for user in &conf.users {
             for stmt in &user.access {
+                // Blocked and absent users should have no keys on these hosts.
+                let keys: Vec<String> = if !stmt.role.authorizes_keys() {
+                    vec![]
+                } else {
+                    user.stmt_keys(stmt)
+                        .filter(|pubkey| pubkey.state != Some(KeyState::Absent))
+                        .map(|pubkey| {
+                            keys::normalize_key(&pubkey.key).unwrap_or_else(|| pubkey.key.clone())
+                        })
+                        .collect()
+                };
+
                 plays.push(AnsiblePlay {
                     name: format!(
-                        "Populate desired pubkey facts for {} on hosts in group {}", stmt.hosts,
-                        user.name
+                        "Populate desired pubkey facts for {} on hosts in group {}",
+                        stmt.hosts, user.name
                     ),
                     hosts: stmt.hosts.clone(),
                     gather_facts: false,
                     r#become: false,
-                    tasks: vec![AnsibleTask {
+                    become_method: None,
+                    connection: None,
+                    become_user: None,
+                    handlers: vec![],
+                    tasks: std::iter::once(AnsibleTask {
                         name: "Populate desired pubkey facts",
                         module: AnsibleModule::set_facts(HashMap::from([(
                             "desired_pubkeys",
                             format!(
-                                "{{{{ desired_pubkeys | default({{}}) | combine({{\"{}\": [\"{}\"]}}) }}}}",
-                                user.name,
-                                user.pubkeys.join("\", \"")
+                                "{{{{ desired_pubkeys | default({{}}) | combine({{{}: {}}}) }}}}",
+                                serde_json::to_string(&user.name)
+                                    .expect("Failed to serialize user name."),
+                                serde_json::to_string(&keys).expect("Failed to serialize pubkeys.")
                             )
                             .into(),
                         )])),
-                        params: HashMap::new(),
-                    }],
+                        params: HashMap::from([("changed_when", false.into())]),
+                    })
+                    // Read by set_actual_pubkey_facts instead of the default path.
+                    .chain(stmt.authorized_keys_file(&user.name).map(|path| AnsibleTask {
+                        name: "Populate authorized_keys path facts",
+                        module: AnsibleModule::set_facts(HashMap::from([(
+                            "authorized_keys_paths",
+                            format!(
+                                "{{{{ authorized_keys_paths | default({{}}) | combine({{{}: {}}}) }}}}",
+                                serde_json::to_string(&user.name)
+                                    .expect("Failed to serialize user name."),
+                                serde_json::to_string(&path).expect("Failed to serialize path.")
+                            )
+                            .into(),
+                        )])),
+                        params: HashMap::from([("changed_when", false.into())]),
+                    }))
+                    .collect(),
                 })
             }
         }
@@ -187,17 +855,23 @@ impl<'a> AnsiblePlay<'a> {
         plays
     }
 
-    pub fn set_actual_pubkey_facts() -> Vec<Self> {
+    /// Sets the `actual_pubkeys` fact on every host to the lines in each account's authorized_keys.
+    /// Keys are read from the path in the `authorized_keys_paths` fact for users that have one.
+    pub fn set_actual_pubkey_facts(hosts: &str) -> Vec<Self> {
         vec![AnsiblePlay {
-            name: "Populate actual pubkey facts for all hosts".to_string(),
-            hosts: "all".to_string(),
+            name: format!("Populate actual pubkey facts for hosts in {hosts}"),
+            hosts: hosts.to_string(),
             gather_facts: false,
             r#become: false,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
             tasks: vec![
                 AnsibleTask {
                     name: "Read contents of passwd db",
                     module: AnsibleModule::getent(HashMap::from([("database", "passwd".into())])),
-                    params: HashMap::new(),
+                    params: HashMap::from([("changed_when", false.into())]),
                 }, // Read pubkey file for each user
                 AnsibleTask {
                     name: "Append username to passwd items",
@@ -209,16 +883,21 @@ impl<'a> AnsiblePlay<'a> {
                         ("loop", "{{ getent_passwd | dict2items }}".into()),
                         ("delegate_to", "localhost".into()),
                         ("run_once", true.into()),
+                        ("changed_when", false.into()),
                     ]),
                 },
                 AnsibleTask {
                     name: "Read authorized_keys for each user",
-                    module: AnsibleModule::slurp("{{ item[4] }}/.ssh/authorized_keys"),
+                    module: AnsibleModule::slurp(
+                        "{{ (authorized_keys_paths | default({}))[item[-1]] \
+                        | default(item[4] ~ '/.ssh/authorized_keys') }}",
+                    ),
                     params: HashMap::from([
                         ("loop", "{{ getent_passwd.values() }}".into()),
                         ("register", "pubkey_files".into()),
                         ("ignore_errors", true.into()),
                         ("become", true.into()),
+                        ("changed_when", false.into()),
                     ]),
                 },
                 AnsibleTask {
@@ -230,55 +909,413 @@ impl<'a> AnsiblePlay<'a> {
                     params: HashMap::from([
                         ("loop", "{{ pubkey_files.results }}".into()),
                         ("when", "item.failed != True".into()),
+                        ("changed_when", false.into()),
                     ]),
                 },
             ],
         }]
     }
 
+    /// Removes the key from the authorized_keys file of every account on every host,
+    /// whether or not the account is managed by sshman, e.g. when the private key has leaked.
+    /// Keys match on their type and data, whatever their options and comment.
+    pub fn revoke_key(key: &str, opts: &PlayOptions) -> Result<Vec<Self>, InvalidKeyError> {
+        let key = keys::normalize_key(key).ok_or_else(|| InvalidKeyError {
+            key: key.to_string(),
+            reason: keys::parse_key(key).err().unwrap_or_default(),
+        })?;
+
+        let mut plays = vec![Self {
+            name: "Revoke public key.".to_string(),
+            hosts: "all".to_string(),
+            gather_facts: false,
+            r#become: true,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks: vec![
+                AnsibleTask {
+                    name: "Read contents of passwd db",
+                    module: AnsibleModule::getent(HashMap::from([("database", "passwd".into())])),
+                    params: HashMap::from([("changed_when", false.into())]),
+                },
+                AnsibleTask {
+                    name: "Remove public key from every account.",
+                    module: AnsibleModule::keys(HashMap::from([
+                        ("user", "{{ item }}".to_string()),
+                        ("key", key),
+                        ("state", "absent".to_string()),
+                        ("manage_dir", "false".to_string()),
+                    ])),
+                    params: HashMap::from([
+                        ("loop", "{{ getent_passwd.keys() | list }}".into()),
+                        // Accounts whose home directory can't be read shouldn't stop the rest.
+                        ("ignore_errors", true.into()),
+                    ]),
+                }
+                .tagged("keys"),
+            ],
+        }];
+
+        opts.apply(&mut plays);
+        Ok(plays)
+    }
+
+    /// Reports the keys in each user's authorized_keys file on the hosts, to import into a config.
+    pub fn import(hosts: &str, opts: &PlayOptions) -> Vec<Self> {
+        let mut plays = Self::set_actual_pubkey_facts(hosts);
+        plays.push(Self {
+            name: "Report actual pubkeys".to_string(),
+            hosts: hosts.to_string(),
+            gather_facts: false,
+            r#become: false,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks: vec![AnsibleTask {
+                name: IMPORT_KEYS_TASK,
+                module: AnsibleModule::debug("{{ actual_pubkeys | default({}) }}"),
+                params: HashMap::new(),
+            }],
+        });
+
+        opts.apply(&mut plays);
+        plays
+    }
+
+    /// Runs the account plays followed by plays reporting the desired and actual keys on each host.
+    /// Intended to be run in check mode to preview the changes a run would make.
+    pub fn diff(conf: &'a SSHConfig, opts: &PlayOptions) -> Vec<Self> {
+        let mut report_plays = Self::set_desired_pubkey_facts(conf);
+        report_plays.extend(Self::set_actual_pubkey_facts("all"));
+        report_plays.push(Self {
+            name: "Report pubkey facts".to_string(),
+            hosts: "all".to_string(),
+            gather_facts: false,
+            r#become: false,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
+            tasks: vec![AnsibleTask {
+                name: KEY_FACTS_TASK,
+                module: AnsibleModule::debug(
+                    "{{ {'desired': desired_pubkeys | default({}), 'actual': actual_pubkeys | default({})} }}",
+                ),
+                params: HashMap::new(),
+            }],
+        });
+
+        opts.apply(&mut report_plays);
+        let mut plays = conf.create_accounts(opts);
+        plays.extend(report_plays);
+        plays
+    }
+
     /// Validates the set of users on each host with authorized public keys against the config.
-    pub fn validate(conf: &'a SSHConfig) -> Vec<Self> {
+    /// Hosts fail if a user has keys that aren't configured, or is missing keys that are.
+    /// If an artifact path is given, the drift on every host is also written there on the
+    /// control machine, as JSON mapping each host to its `extra` and `missing` keys for each user.
+    pub fn validate(conf: &'a SSHConfig, opts: &PlayOptions, artifact: Option<&Path>) -> Vec<Self> {
+        // Written before drift fails its host, so every host's diff is included.
+        let artifact_tasks = artifact.into_iter().flat_map(|path| {
+            [
+                AnsibleTask {
+                    name: "Collect pubkey diff report",
+                    module: AnsibleModule::set_facts(HashMap::from([(
+                        "pubkey_report",
+                        "{{ {'extra': pubkey_diff | default({}), \
+                        'missing': pubkey_missing | default({})} }}"
+                            .into(),
+                    )])),
+                    params: HashMap::from([("changed_when", false.into())]),
+                },
+                AnsibleTask {
+                    name: "Write pubkey diff report",
+                    module: AnsibleModule::copy(
+                        "{{ dict(ansible_play_hosts_all | zip(ansible_play_hosts_all \
+                        | map('extract', hostvars) | map(attribute='pubkey_report', default={}))) \
+                        | to_nice_json }}"
+                            .to_string(),
+                        &path.display().to_string(),
+                    ),
+                    params: HashMap::from([
+                        ("delegate_to", "localhost".into()),
+                        ("run_once", true.into()),
+                        ("changed_when", false.into()),
+                    ]),
+                },
+            ]
+        });
+
         let mut plays = vec![];
         plays.extend(Self::set_desired_pubkey_facts(conf));
-        plays.extend(Self::set_actual_pubkey_facts());
+        plays.extend(Self::set_actual_pubkey_facts("all"));
         plays.extend(vec![Self {
             name: "Validate authorized keys".to_string(),
             hosts: "all".to_string(),
             gather_facts: false,
             r#become: false,
+            become_method: None,
+            connection: None,
+            become_user: None,
+            handlers: vec![],
             tasks: vec![
                 AnsibleTask {
                     name: "Compute differences in desired and actual pubkey lists",
                     module: AnsibleModule::set_facts(HashMap::from([(
                         "_pubkey_diff",
-                        "{{ _pubkey_diff | default({}) | combine({item.key: item.value | reject('in', desired_pubkeys[item.key] | default([]))}) }}"
-                            .into(),
+                        format!(
+                            "{{{{ _pubkey_diff | default({{}}) | combine({{item.key: item.value \
+                            | zip(item.value | map('regex_replace', '{}', '\\\\2')) \
+                            | rejectattr('1', 'in', desired_pubkeys[item.key] | default([])) \
+                            | map(attribute='0') | list}}) }}}}",
+                            normalize_key_pattern()
+                        )
+                        .into(),
                     )])),
-                    params: HashMap::from([("loop", "{{ actual_pubkeys | dict2items }}".into())]),
+                    params: HashMap::from([
+                        ("loop", "{{ actual_pubkeys | dict2items }}".into()),
+                        ("changed_when", false.into()),
+                    ]),
                 },
                 AnsibleTask {
                     name: "Filter pubkey diff list",
                     module: AnsibleModule::set_facts(HashMap::from([(
                         "pubkey_diff",
-                        "{{ pubkey_diff | default({}) | combine({item.key: item.value}) }}"
-                            .into(),
+                        "{{ pubkey_diff | default({}) | combine({item.key: item.value}) }}".into(),
                     )])),
                     params: HashMap::from([
                         ("loop", "{{ _pubkey_diff | dict2items }}".into()),
-                        ("when", "item.value | length > 0".into())
+                        ("when", "item.value | length > 0".into()),
+                        ("changed_when", false.into()),
                     ]),
                 },
-                 AnsibleTask {
-                       name: "Print extra users",
-                       module: AnsibleModule::debug("{{ actual_pubkeys[item.key] }}"),
-                       params: HashMap::from([
-                           ("loop", "{{ pubkey_diff | default({}) | dict2items }}".into()),
-                           ("failed_when", "pubkey_diff | default({}) | length > 0".into()),
-                       ]),
-                   },
-            ],
+                AnsibleTask {
+                    name: "Compute desired pubkeys missing from actual pubkey lists",
+                    module: AnsibleModule::set_facts(HashMap::from([(
+                        "_pubkey_missing",
+                        format!(
+                            "{{{{ _pubkey_missing | default({{}}) | combine({{item.key: item.value \
+                            | reject('in', actual_pubkeys[item.key] | default([]) \
+                            | map('regex_replace', '{}', '\\\\2') | list) | list}}) }}}}",
+                            normalize_key_pattern()
+                        )
+                        .into(),
+                    )])),
+                    params: HashMap::from([
+                        (
+                            "loop",
+                            "{{ desired_pubkeys | default({}) | dict2items }}".into(),
+                        ),
+                        ("changed_when", false.into()),
+                    ]),
+                },
+                AnsibleTask {
+                    name: "Filter missing pubkey list",
+                    module: AnsibleModule::set_facts(HashMap::from([(
+                        "pubkey_missing",
+                        "{{ pubkey_missing | default({}) | combine({item.key: item.value}) }}"
+                            .into(),
+                    )])),
+                    params: HashMap::from([
+                        ("loop", "{{ _pubkey_missing | dict2items }}".into()),
+                        ("when", "item.value | length > 0".into()),
+                        ("changed_when", false.into()),
+                    ]),
+                },
+            ]
+            .into_iter()
+            .chain(artifact_tasks)
+            .chain([
+                AnsibleTask {
+                    name: EXTRA_KEYS_TASK,
+                    module: AnsibleModule::debug("{{ actual_pubkeys[item.key] }}"),
+                    params: HashMap::from([(
+                        "loop",
+                        "{{ pubkey_diff | default({}) | dict2items }}".into(),
+                    )]),
+                },
+                AnsibleTask {
+                    name: MISSING_KEYS_TASK,
+                    module: AnsibleModule::debug("{{ item.value }}"),
+                    params: HashMap::from([(
+                        "loop",
+                        "{{ pubkey_missing | default({}) | dict2items }}".into(),
+                    )]),
+                },
+                // Reported by the tasks above, which loop over each user with drift.
+                AnsibleTask {
+                    name: "Fail on pubkey drift",
+                    module: AnsibleModule::fail("Authorized keys differ from the config."),
+                    params: HashMap::from([(
+                        "when",
+                        "pubkey_diff | default({}) | length > 0 \
+                        or pubkey_missing | default({}) | length > 0"
+                            .into(),
+                    )]),
+                },
+            ])
+            .collect(),
         }]);
 
+        opts.apply(&mut plays);
         plays
     }
+
+    /// Removes the extra keys found by validate, for only the users and hosts they were found on.
+    /// The desired pubkey facts are populated first for any custom authorized_keys paths.
+    pub fn remove_extra_keys(
+        conf: &'a SSHConfig,
+        reports: &[ValidationReport],
+        opts: &PlayOptions,
+    ) -> Vec<Self> {
+        let hosts = reports
+            .iter()
+            .filter(|report| !report.extra_keys.is_empty())
+            .into_group_map_by(|report| &report.host);
+        if hosts.is_empty() {
+            return vec![];
+        }
+
+        let mut plays = Self::set_desired_pubkey_facts(conf);
+        for (host, reports) in hosts.into_iter().sorted_by_key(|(host, _)| *host) {
+            plays.push(Self {
+                name: format!("Remove extra keys on {host}."),
+                hosts: host.clone(),
+                gather_facts: false,
+                r#become: true,
+                become_method: None,
+                connection: None,
+                become_user: None,
+                handlers: vec![],
+                tasks: reports
+                    .into_iter()
+                    .map(|report| {
+                        AnsibleTask {
+                            name: "Remove extra public keys.",
+                            module: AnsibleModule::keys(HashMap::from([
+                                ("user", report.user.clone()),
+                                ("key", report.extra_keys.join("\n")),
+                                ("state", "absent".to_string()),
+                                (
+                                    "path",
+                                    format!(
+                                        "{{{{ (authorized_keys_paths | default({{}}))[{}] \
+                                        | default(omit) }}}}",
+                                        serde_json::to_string(&report.user)
+                                            .expect("Failed to serialize user name.")
+                                    ),
+                                ),
+                                // The file already exists, as the keys were read from it.
+                                ("manage_dir", "false".to_string()),
+                            ])),
+                            params: HashMap::new(),
+                        }
+                        .tagged("keys")
+                    })
+                    .collect(),
+            });
+        }
+
+        opts.apply(&mut plays);
+        plays
+    }
+}
+
+/// Returns a regex matching a key line, with the key type and data in the second group.
+/// Used to compare keys on the host while ignoring their options and comments.
+fn normalize_key_pattern() -> String {
+    format!(
+        "^(.*[ \t])?(({})[ \t]+[A-Za-z0-9+/=]+)([ \t].*)?$",
+        keys::KEY_TYPES.join("|")
+    )
+}
+
+/// Returns the params for a user module task,
+/// including any optional params set on the user or access statement.
+fn account_params<const N: usize>(
+    user: &SSHUser,
+    stmt: &AccessStmt,
+    params: [(&'static str, Value); N],
+) -> HashMap<&'static str, Value> {
+    let mut params = HashMap::from(params);
+
+    if let Some(comment) = &user.comment {
+        params.insert("comment", comment.clone().into());
+    }
+
+    // Super users keep uid 0.
+    if let Some(uid) = user.uid {
+        params.entry("uid").or_insert_with(|| uid.into());
+    }
+
+    if let Some(seuser) = &stmt.seuser {
+        params.insert("seuser", seuser.clone().into());
+    }
+
+    // Replaces the locked password, disabled accounts have none to replace.
+    if let (Some(hash), Some(password)) = (&stmt.password_hash, params.get_mut("password")) {
+        *password = hash.clone().into();
+    }
+
+    if let Some(umask) = &stmt.umask {
+        params.insert("umask", umask.clone().into());
+    }
+
+    // Disabled accounts keep the nologin shell.
+    if let Some(shell) = &stmt.shell {
+        params
+            .entry("shell")
+            .or_insert_with(|| shell.clone().into());
+    }
+
+    if let Some(home) = &stmt.home {
+        params.insert("home", home.clone().into());
+    }
+
+    if let Some(local) = stmt.local {
+        params.insert("local", local.into());
+    }
+
+    if let Some(generate) = stmt.generate_ssh_key {
+        params.insert("generate_ssh_key", generate.into());
+    }
+
+    if let Some(bits) = stmt.ssh_key_bits {
+        params.insert("ssh_key_bits", bits.into());
+    }
+
+    if let Some(key_type) = &stmt.ssh_key_type {
+        params.insert("ssh_key_type", key_type.clone().into());
+    }
+
+    if let Some(expires) = stmt.expiry_timestamp() {
+        params.insert("expires", expires.into());
+    }
+
+    params
+}
+
+/// Returns the params for an authorized_key task, including any optional params set on the access statement.
+fn key_params<const N: usize>(
+    user: &SSHUser,
+    stmt: &AccessStmt,
+    params: [(&'static str, String); N],
+) -> HashMap<&'static str, String> {
+    let mut params = HashMap::from(params);
+
+    if let Some(key_options) = &stmt.key_options {
+        params.insert("key_options", key_options.clone());
+    }
+
+    // The module would otherwise give the user the directory, which is often shared.
+    if let Some(path) = stmt.authorized_keys_file(&user.name) {
+        params.insert("path", path);
+        params.insert("manage_dir", "false".to_string());
+    }
+
+    params
 }
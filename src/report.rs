@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::FoundKeys,
+    error::InvalidReportError,
+    keys,
+    plays::{EXTRA_KEYS_TASK, IMPORT_KEYS_TASK, KEY_FACTS_TASK, MISSING_KEYS_TASK},
+};
+
+/// A format and destination for a Validate report.
+#[derive(Debug, Clone)]
+pub enum Report {
+    /// JUnit XML with one testcase per host.
+    Junit(PathBuf),
+}
+
+impl FromStr for Report {
+    type Err = InvalidReportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("junit", path)) if !path.is_empty() => Ok(Self::Junit(path.into())),
+            _ => Err(InvalidReportError {
+                report: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Differences between a user's authorized_keys on a host and their keys configured with sshman.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    pub host: String,
+    pub user: String,
+    /// Keys on the host that aren't configured.
+    pub extra_keys: Vec<String>,
+    /// Configured keys that aren't on the host, as their type and data.
+    pub missing_keys: Vec<String>,
+}
+
+/// Reads the output of the validate playbook, as printed by the json stdout callback.
+/// Returns every host the playbook ran on, and the drift found on those hosts.
+pub fn parse_validate_output(output: &Value) -> (Vec<String>, Vec<ValidationReport>) {
+    let mut hosts: Vec<String> = output["stats"]
+        .as_object()
+        .map(|stats| stats.keys().cloned().collect())
+        .unwrap_or_default();
+    hosts.sort();
+
+    let mut reports: BTreeMap<(String, String), ValidationReport> = BTreeMap::new();
+    let tasks = output["plays"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|play| play["tasks"].as_array().into_iter().flatten());
+
+    for task in tasks {
+        let missing = match task["task"]["name"].as_str() {
+            Some(EXTRA_KEYS_TASK) => false,
+            Some(MISSING_KEYS_TASK) => true,
+            _ => continue,
+        };
+
+        for (host, result) in task["hosts"].as_object().into_iter().flatten() {
+            for item in result["results"].as_array().into_iter().flatten() {
+                if let Some(user) = item["item"]["key"].as_str() {
+                    let report = reports
+                        .entry((host.clone(), user.to_string()))
+                        .or_insert_with(|| ValidationReport {
+                            host: host.clone(),
+                            user: user.to_string(),
+                            ..Default::default()
+                        });
+                    let keys = string_set(&item["item"]["value"]).into_iter().collect();
+                    if missing {
+                        report.missing_keys = keys;
+                    } else {
+                        report.extra_keys = keys;
+                    }
+                }
+            }
+        }
+    }
+
+    (hosts, reports.into_values().collect())
+}
+
+/// Changes a run would make to a user's access on a host.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UserDiff {
+    pub host: String,
+    pub user: String,
+    /// Whether the user's account would be created or modified.
+    pub account_changed: bool,
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+/// Reads the output of the diff playbook run in check mode, as printed by the json stdout callback.
+pub fn parse_diff_output(output: &Value) -> Vec<UserDiff> {
+    let mut diffs: BTreeMap<(String, String), UserDiff> = BTreeMap::new();
+
+    let tasks = output["plays"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|play| play["tasks"].as_array().into_iter().flatten());
+
+    for task in tasks {
+        for (host, result) in task["hosts"].as_object().into_iter().flatten() {
+            if result["action"] == "ansible.builtin.user" && result["changed"] == true {
+                if let Some(user) = result["name"].as_str() {
+                    diff_entry(&mut diffs, host, user).account_changed = true;
+                }
+            }
+
+            if task["task"]["name"] != KEY_FACTS_TASK {
+                continue;
+            }
+
+            for (user, desired) in result["msg"]["desired"].as_object().into_iter().flatten() {
+                // Desired keys are already normalized, actual keys may have options or comments.
+                let desired = string_set(desired);
+                let actual = string_set(&result["msg"]["actual"][user]);
+                let normalized = |key: &String| keys::normalize_key(key).unwrap_or(key.clone());
+                let actual_normalized: BTreeSet<String> = actual.iter().map(normalized).collect();
+
+                let diff = diff_entry(&mut diffs, host, user);
+                diff.add = desired.difference(&actual_normalized).cloned().collect();
+                diff.remove = actual
+                    .iter()
+                    .filter(|key| !desired.contains(&normalized(key)))
+                    .cloned()
+                    .collect();
+                diff.unchanged = desired.intersection(&actual_normalized).cloned().collect();
+            }
+        }
+    }
+
+    diffs.into_values().collect()
+}
+
+/// Reads the keys found on each host from the output of the import playbook,
+/// as printed by the json stdout callback. Users without any keys are left out.
+pub fn parse_import_output(output: &Value) -> Vec<FoundKeys> {
+    let mut found = vec![];
+
+    let tasks = output["plays"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|play| play["tasks"].as_array().into_iter().flatten())
+        .filter(|task| task["task"]["name"] == IMPORT_KEYS_TASK);
+
+    for task in tasks {
+        for (host, result) in task["hosts"].as_object().into_iter().flatten() {
+            for (user, keys) in result["msg"].as_object().into_iter().flatten() {
+                let keys = keys
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty() && !key.starts_with('#'))
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if !keys.is_empty() {
+                    found.push(FoundKeys {
+                        host: host.clone(),
+                        user: user.clone(),
+                        keys,
+                    });
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// Returns the diff for the user on the host, inserting an empty one if necessary.
+fn diff_entry<'a>(
+    diffs: &'a mut BTreeMap<(String, String), UserDiff>,
+    host: &str,
+    user: &str,
+) -> &'a mut UserDiff {
+    diffs
+        .entry((host.to_string(), user.to_string()))
+        .or_insert_with(|| UserDiff {
+            host: host.to_string(),
+            user: user.to_string(),
+            ..Default::default()
+        })
+}
+
+/// Collects the strings in a JSON array.
+fn string_set(value: &Value) -> BTreeSet<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_str().map(str::to_string))
+        .collect()
+}
+
+/// Renders a JUnit XML document with a testcase for each host, failing if the host has drift.
+pub fn junit(hosts: &[String], reports: &[ValidationReport]) -> String {
+    let failures = hosts
+        .iter()
+        .filter(|host| reports.iter().any(|report| &report.host == *host))
+        .count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuite name=\"ansible-sshman validate\" tests=\"{}\" failures=\"{failures}\">",
+        hosts.len()
+    )
+    .unwrap();
+
+    for host in hosts {
+        let drift = reports
+            .iter()
+            .filter(|report| &report.host == host)
+            .collect::<Vec<_>>();
+
+        if drift.is_empty() {
+            writeln!(xml, "  <testcase name=\"{}\"/>", escape(host)).unwrap();
+            continue;
+        }
+
+        let message = drift
+            .iter()
+            .flat_map(|report| {
+                [
+                    ("extra", &report.extra_keys),
+                    ("missing", &report.missing_keys),
+                ]
+                .into_iter()
+                .filter(|(_, keys)| !keys.is_empty())
+                .map(|(label, keys)| {
+                    format!("{} has {label} keys:\n{}", report.user, keys.join("\n"))
+                })
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        writeln!(xml, "  <testcase name=\"{}\">", escape(host)).unwrap();
+        writeln!(
+            xml,
+            "    <failure message=\"Key drift detected\">{}</failure>",
+            escape(&message)
+        )
+        .unwrap();
+        writeln!(xml, "  </testcase>").unwrap();
+    }
+
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// Escapes text for use in XML attributes and content.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -1,125 +1,719 @@
 use core::str;
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    io::Write,
-    path::Path,
-    process::Command,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
-use serde_yaml::Value;
+use anyhow::Context;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use serde::Deserialize;
 use tempfile::NamedTempFile;
 
-use crate::{error::InvOutputParseError, model::AnsiblePlay};
+use crate::{
+    config::Connection,
+    error::{
+        InvOutputParseError, InvalidExtraVarError, InventoryTimeoutError, PlaybookError,
+        PlaybookFailure, PlaybookTestError, SudoersError, TransformError,
+    },
+    inventory::Inventory,
+    model::{AnsiblePlay, PlaybookImport},
+    report::ValidationReport,
+};
 
-pub fn run_plays(plays: &[AnsiblePlay], args: &[String]) {
-    let mut outfile = NamedTempFile::new().expect("Failed to create temp file.");
+/// Delay before the first retry of ansible-playbook, doubled for each retry after.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
 
-    outfile
-        .write_all(
-            serde_yaml::to_string(plays)
-                .expect("Failed to serialize playbook.")
-                .as_bytes(),
-        )
-        .expect("Failed to write playbook to temp file.");
+/// Where the playbook is written for ansible-pull, outside of the checkout it makes.
+pub const PULL_PLAYBOOK: &str = "/var/lib/ansible-sshman/playbook.yml";
 
-    run_playbook(args, outfile.path()).expect("Failed to run playbook");
+/// How often to check whether ansible-playbook has exited when it has a timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Options controlling how generated playbooks are serialized and run.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Shell command to pipe the playbook through before it is used.
+    pub transform: Option<String>,
+    /// Report what would be done instead of spawning any subprocesses.
+    pub dry_run: bool,
+    /// Run ansible-playbook in check mode, reporting changes instead of making them.
+    pub check: bool,
+    /// Host pattern to pass to ansible-playbook with --limit.
+    pub limit: Option<String>,
+    /// Connection settings to pass to ansible-playbook as extra vars.
+    pub connection: Connection,
+    /// Connection plugin to pass to ansible-playbook with --connection, e.g. `local`.
+    pub connection_type: Option<String>,
+    /// Number of hosts ansible-playbook manages in parallel, if not its default.
+    pub forks: Option<NonZeroU32>,
+    /// Times to retry ansible-playbook if it can't be started or times out.
+    pub retries: u32,
+    /// How long ansible-playbook may run before it is killed.
+    pub timeout: Option<Duration>,
+    /// Have ansible-playbook prompt for the password to become root with.
+    pub ask_become_pass: bool,
+    /// File ansible-playbook reads the vault password from.
+    pub vault_password_file: Option<String>,
+    /// YAML or JSON file of variables to pass to ansible-playbook as extra vars.
+    pub extra_vars_file: Option<String>,
+    /// Variables to pass to ansible-playbook as extra vars, after those from the file.
+    pub extra_vars: Vec<ExtraVar>,
+    /// Repository URL to run plays with ansible-pull from, instead of pushing with ansible-playbook.
+    pub pull: Option<String>,
+    /// Reprint ansible's output line by line with a running task count,
+    /// instead of passing it through with its colours.
+    pub progress: bool,
 }
 
-fn run_playbook(args: &[String], path: &Path) -> anyhow::Result<()> {
-    Command::new("ansible-playbook")
-        .args(args)
-        .arg(path)
-        .spawn()?
-        .wait()?;
+/// Counts the tasks ansible has started from its output, to prefix each line with.
+#[derive(Debug, Default)]
+pub struct Progress {
+    tasks: usize,
+}
 
-    Ok(())
+impl Progress {
+    /// Returns the line of ansible's output to print, or nothing for a blank line.
+    /// The line is prefixed with the number of tasks started so far,
+    /// and the padding of task and play headers is trimmed.
+    pub fn format(&mut self, line: &str) -> Option<String> {
+        let line = line.trim_end_matches(['*', ' ']);
+        if line.is_empty() {
+            return None;
+        }
+        if line.starts_with("TASK [") || line.starts_with("RUNNING HANDLER [") {
+            self.tasks += 1;
+        }
+        Some(format!("sshman [task {}] {line}", self.tasks))
+    }
 }
 
-/// Returns a list of hosts and their ansible_host var if set.
-pub fn list_hosts(pattern: &str) -> anyhow::Result<HashMap<String, Option<String>>> {
-    let output = Command::new("ansible-inventory")
-        .args(vec!["--list", "--yaml", "--limit", pattern])
-        .output()?;
+/// A variable given on the command line as `KEY=VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraVar {
+    pub key: String,
+    pub value: String,
+}
 
-    let mut hosts: HashMap<String, Option<String>> = HashMap::new();
+impl FromStr for ExtraVar {
+    type Err = InvalidExtraVarError;
 
-    for hostvar_map in group_hosts(&output.stdout)? {
-        for (host_val, vars) in hostvar_map {
-            match host_val {
-                Value::String(string) => match hosts.entry(string) {
-                    Entry::Occupied(mut entry) => {
-                        if entry.get().is_none() {
-                            entry.insert(hostname_from_vars(vars));
-                        }
-                    }
-                    Entry::Vacant(entry) => {
-                        entry.insert(hostname_from_vars(vars));
-                    }
-                },
-                _ => {
-                    return Err(InvOutputParseError {
-                        message: "Expected string keys in a group listing.".to_string(),
-                    }
-                    .into())
-                }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value))
+                if key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                Ok(Self {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+            }
+            _ => Err(InvalidExtraVarError { var: s.to_string() }),
+        }
+    }
+}
+
+impl RunOptions {
+    /// Serializes the plays, applying the transform unless this is a dry run.
+    pub fn serialize(&self, plays: &[AnsiblePlay]) -> anyhow::Result<String> {
+        for play in plays {
+            debug!("Generated play {:?} for hosts {}", play.name, play.hosts);
+        }
+
+        if self.dry_run {
+            if let Some(transform) = &self.transform {
+                println!("Would pipe playbook through: {transform}");
             }
+            serialize_plays(plays, None)
+        } else {
+            serialize_plays(plays, self.transform.as_deref())
+        }
+    }
+
+    /// Returns the arguments to pass to ansible-playbook, including the user provided ones.
+    pub fn playbook_args(&self, args: &[String]) -> Vec<String> {
+        let mut all_args = vec![];
+        if self.check {
+            all_args.extend(["--check".to_string(), "--diff".to_string()]);
+        }
+        if let Some(limit) = &self.limit {
+            all_args.extend(["--limit".to_string(), limit.clone()]);
+        }
+        if let Some(connection) = &self.connection_type {
+            all_args.extend(["--connection".to_string(), connection.clone()]);
+        }
+        // ansible-pull only manages the host it runs on, and its -f means --force.
+        if let Some(forks) = self.forks.filter(|_| self.pull.is_none()) {
+            all_args.extend(["--forks".to_string(), forks.to_string()]);
         }
+        if self.ask_become_pass {
+            all_args.push("--ask-become-pass".to_string());
+        }
+        if let Some(path) = &self.vault_password_file {
+            all_args.extend(["--vault-password-file".to_string(), path.clone()]);
+        }
+        if let Some(path) = &self.extra_vars_file {
+            all_args.extend(["-e".to_string(), format!("@{path}")]);
+        }
+        let vars = self.connection.extra_vars();
+        if !vars.is_empty() {
+            all_args.extend([
+                "-e".to_string(),
+                serde_json::to_string(&vars).expect("Failed to serialize extra vars."),
+            ]);
+        }
+        // Passed as JSON rather than KEY=VALUE, which ansible splits on whitespace.
+        if !self.extra_vars.is_empty() {
+            let vars = self
+                .extra_vars
+                .iter()
+                .map(|var| (var.key.as_str(), var.value.as_str()))
+                .collect::<BTreeMap<_, _>>();
+            all_args.extend([
+                "-e".to_string(),
+                serde_json::to_string(&vars).expect("Failed to serialize extra vars."),
+            ]);
+        }
+        all_args.extend_from_slice(args);
+        all_args
+    }
+}
+
+/// Runs the plays with ansible-playbook and returns its exit status.
+pub fn run_plays(
+    plays: &[AnsiblePlay],
+    args: &[String],
+    opts: &RunOptions,
+) -> anyhow::Result<ExitStatus> {
+    if let Some(url) = &opts.pull {
+        return run_pull(plays, url, args, opts);
+    }
+    let outfile = write_playbook(plays, opts)?;
+
+    if opts.dry_run {
+        // Keep the playbook so the printed command can be inspected or run by hand.
+        let (_, path) = outfile.keep()?;
+        println!("Wrote playbook to {}", path.display());
+        println!(
+            "Would run: {}",
+            playbook_command(&opts.playbook_args(args), &path)
+                .iter()
+                .map(|arg| quote(arg))
+                .join(" ")
+        );
+        return Ok(ExitStatus::default());
+    }
+
+    run_playbook(&opts.playbook_args(args), outfile.path(), opts)
+}
+
+/// Returns the ansible-playbook command line that runs the playbook at the path, program first.
+pub fn playbook_command(args: &[String], path: &Path) -> Vec<String> {
+    let mut command = vec!["ansible-playbook".to_string()];
+    command.extend_from_slice(args);
+    command.push(path.display().to_string());
+    command
+}
+
+/// Returns the ansible-pull command line that checks out the repository and runs the playbook
+/// at the path, program first.
+/// An absolute path is used as is rather than looked up in the checkout.
+pub fn pull_command(url: &str, args: &[String], path: &Path) -> Vec<String> {
+    let mut command = vec![
+        "ansible-pull".to_string(),
+        "--url".to_string(),
+        url.to_string(),
+    ];
+    command.extend_from_slice(args);
+    command.push(path.display().to_string());
+    command
+}
+
+/// Writes the playbook to [`PULL_PLAYBOOK`] and runs it on this host with ansible-pull.
+fn run_pull(
+    plays: &[AnsiblePlay],
+    url: &str,
+    args: &[String],
+    opts: &RunOptions,
+) -> anyhow::Result<ExitStatus> {
+    let path = Path::new(PULL_PLAYBOOK);
+    let command = pull_command(url, &opts.playbook_args(args), path);
+
+    let playbook = opts.serialize(plays)?;
+    if opts.dry_run {
+        println!("Would write playbook to {PULL_PLAYBOOK}");
+        println!(
+            "Would run: {}",
+            command.iter().map(|arg| quote(arg)).join(" ")
+        );
+        return Ok(ExitStatus::default());
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
     }
+    fs::write(path, playbook)
+        .with_context(|| format!("Failed to write playbook to {PULL_PLAYBOOK}"))?;
 
-    Ok(hosts)
+    info!("Running {}", command.join(" "));
+    run_passthrough(Command::new(&command[0]).args(&command[1..]), opts)
 }
 
-fn hostname_from_vars(vars: Value) -> Option<String> {
-    if let Value::String(hostname) = &vars["ansible_hostname"] {
-        Some(hostname.into())
-    } else if let Value::String(hostname) = &vars["inventory_hostname"] {
-        Some(hostname.into())
-    } else if let Value::String(hostname) = &vars["ansible_host"] {
-        Some(hostname.into())
-    } else if let Value::String(hostname) = &vars["address"] {
-        Some(hostname.into())
+/// Quotes an argument for a POSIX shell if it contains any special characters.
+fn quote(arg: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        Cow::Borrowed(arg)
     } else {
-        None
-    }
-}
-
-/// Transforms the `ansible-inventory --list` output into a list of host->vars mappings.
-fn group_hosts(output: &[u8]) -> anyhow::Result<Vec<HashMap<Value, Value>>> {
-    let mut maps = vec![];
-
-    match serde_yaml::from_slice(output)? {
-        Value::Mapping(root) => {
-            if let Some(Value::Mapping(all)) = root.get("all") {
-                if let Some(Value::Mapping(children)) = all.get("children") {
-                    for (_, group_) in children {
-                        match group_ {
-                            Value::Mapping(group) => {
-                                if let Some(Value::Mapping(group_hosts)) = group.get("hosts") {
-                                    maps.push(HashMap::from_iter(
-                                        group_hosts
-                                            .iter()
-                                            .map(|(k, v)| (k.to_owned(), v.to_owned())),
-                                    ));
-                                }
-                            }
-                            _ => return Err(InvOutputParseError {
-                                message:
-                                    "Expected a mapping of hosts to host vars in a group listing."
-                                        .to_string(),
-                            }
-                            .into()),
-                        }
+        Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+    }
+}
+
+/// Runs the plays with the json stdout callback and returns the parsed output and exit status.
+/// Its stderr is not captured, so if ansible fails before printing any JSON its errors are
+/// shown to the user, and the parse error gives the exit status.
+pub fn run_plays_json(
+    plays: &[AnsiblePlay],
+    args: &[String],
+    opts: &RunOptions,
+) -> anyhow::Result<(serde_json::Value, ExitStatus)> {
+    let outfile = write_playbook(plays, opts)?;
+
+    let command = playbook_command(&opts.playbook_args(args), outfile.path());
+    info!("Running {} with the json callback", command.join(" "));
+    let (status, stdout) = run_with_retries(
+        Command::new(&command[0])
+            .env("ANSIBLE_STDOUT_CALLBACK", "json")
+            .args(&command[1..])
+            .stdout(Stdio::piped()),
+        opts,
+    )?;
+
+    let output = serde_json::from_slice(&stdout).with_context(|| {
+        format!("Failed to parse the JSON output of ansible-playbook, which exited with {status}")
+    })?;
+    Ok((output, status))
+}
+
+/// Checks the plays with `ansible-playbook --syntax-check`, then runs them in check mode against
+/// the inventory, stopping at the first stage that fails.
+/// Ansible's output is captured, and only shown as part of the error if a stage fails.
+pub fn test_plays(
+    plays: &[AnsiblePlay],
+    inventory: &str,
+    args: &[String],
+    opts: &RunOptions,
+) -> anyhow::Result<()> {
+    let outfile = write_playbook(plays, opts)?;
+    let stages = test_commands(inventory, args, outfile.path(), opts);
+
+    if opts.dry_run {
+        let (_, path) = outfile.keep()?;
+        println!("Wrote playbook to {}", path.display());
+        for (_, command) in stages {
+            println!(
+                "Would run: {}",
+                command.iter().map(|arg| quote(arg)).join(" ")
+            );
+        }
+        return Ok(());
+    }
+
+    for (stage, command) in stages {
+        info!("Running {}", command.join(" "));
+        // Errors such as a bad playbook are printed to stderr rather than stdout.
+        let stderr = NamedTempFile::new()?;
+        let (status, stdout) = run_with_retries(
+            Command::new(&command[0])
+                .args(&command[1..])
+                .stdout(Stdio::piped())
+                .stderr(stderr.reopen()?),
+            opts,
+        )?;
+        if !status.success() {
+            let output =
+                String::from_utf8_lossy(&stdout).into_owned() + &fs::read_to_string(stderr.path())?;
+            return Err(PlaybookTestError {
+                stage,
+                status,
+                output: output.trim_end().to_string(),
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the name and ansible-playbook command line of each stage of testing the playbook
+/// at the path against the inventory.
+pub fn test_commands(
+    inventory: &str,
+    args: &[String],
+    path: &Path,
+    opts: &RunOptions,
+) -> [(&'static str, Vec<String>); 2] {
+    [
+        ("syntax check", "--syntax-check"),
+        ("check mode run", "--check"),
+    ]
+    .map(|(stage, flag)| {
+        let mut stage_args = vec!["-i".to_string(), inventory.to_string(), flag.to_string()];
+        stage_args.extend_from_slice(args);
+        (
+            stage,
+            playbook_command(&opts.playbook_args(&stage_args), path),
+        )
+    })
+}
+
+/// Writes the playbook to a temp file which is deleted when dropped.
+fn write_playbook(plays: &[AnsiblePlay], opts: &RunOptions) -> anyhow::Result<NamedTempFile> {
+    let playbook = opts.serialize(plays)?;
+    let mut outfile = NamedTempFile::new().context("Failed to create temp file")?;
+    outfile
+        .write_all(playbook.as_bytes())
+        .context("Failed to write playbook to temp file")?;
+
+    Ok(outfile)
+}
+
+/// Writes each play to its own numbered file in the directory, which is created if needed,
+/// and an `index.yml` playbook that imports them in order.
+/// Returns the path of the index playbook.
+pub fn write_split_playbook(
+    plays: &[AnsiblePlay],
+    dir: &Path,
+    opts: &RunOptions,
+) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create playbook directory {}", dir.display()))?;
+
+    let width = plays.len().to_string().len().max(2);
+    let mut imports = vec![];
+    for (i, play) in plays.iter().enumerate() {
+        let slug = play
+            .name
+            .to_lowercase()
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .join("-");
+        let file = format!("{:0width$}-{slug}.yml", i + 1);
+
+        let path = dir.join(&file);
+        fs::write(&path, opts.serialize(std::slice::from_ref(play))?)
+            .with_context(|| format!("Failed to write play to {}", path.display()))?;
+        imports.push(PlaybookImport {
+            import_playbook: file,
+        });
+    }
+
+    let index = dir.join("index.yml");
+    fs::write(&index, serde_yaml::to_string(&imports)?)
+        .with_context(|| format!("Failed to write playbook to {}", index.display()))?;
+    Ok(index)
+}
+
+/// Serializes the plays to YAML.
+/// If a transform command is given the YAML is piped through it and its output is used instead.
+pub fn serialize_plays(plays: &[AnsiblePlay], transform: Option<&str>) -> anyhow::Result<String> {
+    let playbook = serde_yaml::to_string(plays)?;
+
+    match transform {
+        Some(command) => transform_playbook(command, playbook),
+        None => Ok(playbook),
+    }
+}
+
+/// Runs the command in a shell with the playbook on stdin and returns its stdout.
+fn transform_playbook(command: &str, playbook: String) -> anyhow::Result<String> {
+    info!("Piping playbook through sh -c {command:?}");
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Write from another thread so a transformer that streams output can't deadlock us.
+    let mut stdin = child.stdin.take().expect("Child stdin was not piped.");
+    let writer = thread::spawn(move || stdin.write_all(playbook.as_bytes()));
+
+    let output = child.wait_with_output()?;
+    let written = writer.join().expect("Failed to join stdin writer.");
+
+    // A command that exits early closes its stdin, so report its status over the broken pipe.
+    if !output.status.success() {
+        return Err(TransformError {
+            command: command.to_string(),
+            status: output.status,
+        }
+        .into());
+    }
+    written?;
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn run_playbook(args: &[String], path: &Path, opts: &RunOptions) -> anyhow::Result<ExitStatus> {
+    let command = playbook_command(args, path);
+    info!("Running {}", command.join(" "));
+    run_passthrough(Command::new(&command[0]).args(&command[1..]), opts)
+}
+
+/// Runs an ansible command whose output is for the user, with retries.
+/// The output passes straight through unless progress is asked for.
+pub fn run_passthrough(command: &mut Command, opts: &RunOptions) -> anyhow::Result<ExitStatus> {
+    let (status, _) = if opts.progress {
+        retry(command.stdout(Stdio::piped()), opts, |command, timeout| {
+            run_with_timeout(command, timeout, true)
+        })?
+    } else {
+        run_with_retries(command, opts)?
+    };
+    Ok(status)
+}
+
+/// Runs the command, retrying with exponential backoff if it can't be spawned or times out.
+/// Returns its exit status and stdout, which is empty unless it was piped.
+/// A non-zero exit is not retried, as ansible has already acted on the hosts it could reach.
+pub fn run_with_retries(
+    command: &mut Command,
+    opts: &RunOptions,
+) -> Result<(ExitStatus, Vec<u8>), PlaybookError> {
+    retry(command, opts, |command, timeout| {
+        run_with_timeout(command, timeout, false)
+    })
+}
+
+/// Runs the command with `run` until it succeeds or runs out of retries, backing off after each.
+/// Failing to read the output is not retried, as the command has already run.
+fn retry<T>(
+    command: &mut Command,
+    opts: &RunOptions,
+    run: impl Fn(&mut Command, Option<Duration>) -> Result<T, PlaybookFailure>,
+) -> Result<T, PlaybookError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let failure = match run(command, opts.timeout) {
+            Ok(output) => return Ok(output),
+            Err(failure) => failure,
+        };
+
+        if attempts > opts.retries || matches!(failure, PlaybookFailure::Output(_)) {
+            return Err(PlaybookError { attempts, failure });
+        }
+        let backoff = RETRY_BACKOFF * 2u32.saturating_pow(attempts - 1);
+        warn!("Attempt {attempts} to run {command:?} failed, retrying in {backoff:?}: {failure:?}");
+        thread::sleep(backoff);
+    }
+}
+
+/// Runs the command once, killing it if it runs for longer than the timeout.
+/// With `progress` its piped stdout is printed with [`Progress`] as it is read, and not returned.
+/// Lines that aren't UTF-8 are printed with the invalid bytes replaced.
+fn run_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    progress: bool,
+) -> Result<(ExitStatus, Vec<u8>), PlaybookFailure> {
+    let mut child = command.spawn().map_err(PlaybookFailure::Spawn)?;
+
+    // Read stdout from another thread so a full pipe can't block the child.
+    let reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = vec![];
+            if progress {
+                let mut counter = Progress::default();
+                let mut stdout = BufReader::new(stdout);
+                let mut line = vec![];
+                while stdout.read_until(b'\n', &mut line)? > 0 {
+                    let text = String::from_utf8_lossy(&line);
+                    if let Some(text) = counter.format(text.trim_end_matches(['\r', '\n'])) {
+                        println!("{text}");
                     }
+                    line.clear();
                 }
+            } else {
+                stdout.read_to_end(&mut output)?;
             }
-        }
-        _ => {
-            return Err(InvOutputParseError {
-                message: "Expected a mapping from the root of the output.".to_string(),
+            Ok(output)
+        })
+    });
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let status = loop {
+        match child.try_wait().map_err(PlaybookFailure::Spawn)? {
+            Some(status) => break status,
+            None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                child.kill().map_err(PlaybookFailure::Spawn)?;
+                child.wait().map_err(PlaybookFailure::Spawn)?;
+                return Err(PlaybookFailure::Timeout(timeout.unwrap_or_default()));
             }
-            .into())
+            None => thread::sleep(POLL_INTERVAL),
         }
     };
 
-    Ok(maps)
+    let stdout = match reader {
+        Some(reader) => reader
+            .join()
+            .expect("Failed to join stdout reader.")
+            .map_err(PlaybookFailure::Output)?,
+        None => vec![],
+    };
+    Ok((status, stdout))
+}
+
+/// Checks sudoers content for a group with `visudo` on this machine,
+/// so mistakes are found before the file is sent to any host.
+pub fn validate_sudoers(group: &str, content: &str) -> anyhow::Result<()> {
+    let mut file = NamedTempFile::new()?;
+    file.write_all(content.as_bytes())?;
+
+    info!("Running visudo -cf on the sudoers file for {group}");
+    let output = Command::new("visudo")
+        .arg("-cf")
+        .arg(file.path())
+        .output()
+        .context("Failed to run visudo")?;
+
+    if !output.status.success() {
+        let mut message = String::from_utf8_lossy(&output.stdout).into_owned();
+        message.push_str(&String::from_utf8_lossy(&output.stderr));
+        return Err(SudoersError {
+            group: group.to_string(),
+            output: message.trim().to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Drift on a host as written to the validate playbook's artifact, keyed by user.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct HostDrift {
+    extra: BTreeMap<String, Vec<String>>,
+    missing: BTreeMap<String, Vec<String>>,
+}
+
+/// Reads the JSON report written by the validate playbook when given an artifact path,
+/// which maps each host to the extra and missing keys for each of its users.
+/// Returns the reports sorted by host and then user.
+pub fn read_validation_artifact(path: &Path) -> anyhow::Result<Vec<ValidationReport>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read validation report from {}", path.display()))?;
+    let hosts: BTreeMap<String, HostDrift> = serde_json::from_str(&content)
+        .with_context(|| format!("Invalid validation report in {}", path.display()))?;
+
+    let mut reports = vec![];
+    for (host, mut drift) in hosts {
+        let users = drift
+            .extra
+            .keys()
+            .chain(drift.missing.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for user in users {
+            reports.push(ValidationReport {
+                host: host.clone(),
+                extra_keys: drift.extra.remove(&user).unwrap_or_default(),
+                missing_keys: drift.missing.remove(&user).unwrap_or_default(),
+                user,
+            });
+        }
+    }
+    Ok(reports)
+}
+
+/// Reads the whole inventory with a single call to ansible-inventory.
+/// Dynamic inventory plugins can stall, so it is killed if it runs for longer than the timeout.
+pub fn full_inventory(timeout: Option<Duration>) -> anyhow::Result<Inventory> {
+    info!("Running ansible-inventory --list --yaml");
+    read_inventory(
+        Command::new("ansible-inventory").args(["--list", "--yaml"]),
+        timeout,
+    )
+}
+
+/// Runs the command and parses the inventory it prints as YAML,
+/// killing it if it runs for longer than the timeout.
+/// Its stderr is not captured, so any problems it reports are shown to the user.
+/// Bytes that aren't UTF-8 are replaced, and warnings printed before the inventory are skipped.
+pub fn read_inventory(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Inventory> {
+    let (status, stdout) = match run_with_timeout(command.stdout(Stdio::piped()), timeout, false) {
+        Ok(output) => output,
+        Err(PlaybookFailure::Spawn(err)) => {
+            return Err(anyhow::Error::new(err).context(format!("Failed to run {command:?}")))
+        }
+        Err(PlaybookFailure::Output(err)) => {
+            return Err(anyhow::Error::new(err).context(format!("Failed to read {command:?}")))
+        }
+        Err(PlaybookFailure::Timeout(timeout)) => {
+            return Err(InventoryTimeoutError { timeout }.into())
+        }
+    };
+    debug!(
+        "{command:?} exited with {status} and printed {} bytes",
+        stdout.len()
+    );
+    if !status.success() {
+        return Err(InvOutputParseError::new(
+            format!("ansible-inventory exited with {status}"),
+            &stdout,
+        )
+        .into());
+    }
+
+    let output = String::from_utf8_lossy(&stdout);
+    if let Cow::Owned(_) = output {
+        warn!("{command:?} printed bytes that aren't UTF-8, which have been replaced");
+    }
+    let inventory = Inventory::from_yaml(skip_warnings(&output))
+        .map_err(|err| InvOutputParseError::new(err.to_string(), &stdout))?;
+
+    Ok(inventory)
+}
+
+/// Returns the output from the start of the YAML document, skipping any warnings ansible
+/// printed to stdout before it, e.g. `[DEPRECATION WARNING]: ...` and its wrapped lines.
+/// The document starts at a `---` line or an unindented `key:` line, e.g. `all:`.
+/// Output that doesn't start with a warning, or has no such line, is returned as is.
+pub fn skip_warnings(output: &str) -> &str {
+    if !output.starts_with('[') {
+        return output;
+    }
+
+    let mut offset = 0;
+    for line in output.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed == "---"
+            || (trimmed.ends_with(':')
+                && !trimmed.contains(char::is_whitespace)
+                && !trimmed.starts_with('['))
+        {
+            warn!(
+                "Skipping what ansible-inventory printed before the inventory: {}",
+                output[..offset].trim_end()
+            );
+            return &output[offset..];
+        }
+        offset += line.len();
+    }
+
+    output
 }
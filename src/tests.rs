@@ -1,18 +1,2577 @@
+use itertools::Itertools;
 use pretty_assertions::assert_eq;
 use serde_yaml::Value;
-use std::fs;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    num::{NonZeroU32, NonZeroUsize},
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
 
-use crate::config::SSHConfig;
+use crate::{
+    config::{
+        parse_config, read_config, read_configs, AccessStmt, CheckOptions, Connection, PubKey,
+        Role, SSHConfig, SSHUser, Severity, CONFIG_VERSION,
+    },
+    error::{InvOutputParseError, InventoryTimeoutError, PlaybookFailure, TransformError},
+    graph,
+    identity::{self, GroupAccess, Memberships},
+    inventory::Inventory,
+    keys,
+    model::{AnsibleModule, AnsiblePlay, AnsibleTask},
+    plays::{
+        HostLimit, PlayOptions, EXTRA_KEYS_TASK, IMPORT_KEYS_TASK, KEY_FACTS_TASK,
+        MISSING_KEYS_TASK,
+    },
+    report::{self, UserDiff, ValidationReport},
+    subprocess::{
+        playbook_command, pull_command, read_inventory, read_validation_artifact, run_passthrough,
+        run_plays, run_plays_json, run_with_retries, serialize_plays, skip_warnings, test_commands,
+        test_plays, write_split_playbook, ExtraVar, Progress, RunOptions, PULL_PLAYBOOK,
+    },
+};
 
 #[test]
 fn test_playbook_output() {
     let conf: SSHConfig =
         serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
 
-    let actual_playbook = serde_yaml::to_value(conf.create_accounts()).unwrap();
+    let actual_playbook =
+        serde_yaml::to_value(conf.create_accounts(&PlayOptions::default())).unwrap();
 
     let expected_playbook: Value =
         serde_yaml::from_str(&fs::read_to_string("test/playbook.yml").unwrap()).unwrap();
 
     assert_eq!(actual_playbook, expected_playbook);
 }
+
+#[test]
+fn test_revoke_key() {
+    let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc";
+    let plays = AnsiblePlay::revoke_key(
+        &format!("no-pty {key} leaked@laptop"),
+        &PlayOptions {
+            limit: Some("web".parse().unwrap()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(plays.len(), 1);
+    assert_eq!(plays[0].hosts, "all:&web");
+    let remove = &plays[0].tasks[1];
+    assert_eq!(remove.module.params["key"], Value::from(key));
+    assert_eq!(remove.module.params["state"], Value::from("absent"));
+    assert!(remove.params["loop"]
+        .as_str()
+        .unwrap()
+        .contains("getent_passwd"));
+
+    let err = AnsiblePlay::revoke_key("ssh-ed25519", &PlayOptions::default()).unwrap_err();
+    assert_eq!(err.reason, "missing key data");
+}
+
+#[test]
+fn test_since_changes() {
+    let old = parse_config(
+        "old",
+        r#"
+- name: alice
+  pubkeys: [ssh-ed25519 AAAA alice]
+  access: [{hosts: web, role: sudoer}]
+- name: bob
+  pubkeys: [ssh-ed25519 BBBB bob]
+  access: [{hosts: web, role: disabled}]
+- name: carol
+  pubkeys: [ssh-ed25519 CCCC carol]
+  access: [{hosts: db, role: sudoer}]
+"#,
+    )
+    .unwrap();
+    let new = parse_config(
+        "new",
+        r#"
+- name: alice
+  pubkeys: [ssh-ed25519 AAAA alice]
+  access: [{hosts: web, role: sudoer}]
+- name: bob
+  pubkeys: [ssh-ed25519 BBBB bob]
+  access: [{hosts: web, role: sudoer}]
+- name: dave
+  pubkeys: [ssh-ed25519 DDDD dave]
+  access: [{hosts: db, role: disabled}]
+"#,
+    )
+    .unwrap();
+
+    let changes = new.diff(&old);
+    assert_eq!(changes.added.iter().collect_vec(), ["dave"]);
+    assert_eq!(changes.changed.iter().collect_vec(), ["bob"]);
+    assert_eq!(
+        changes.removed.iter().map(|user| &user.name).collect_vec(),
+        ["carol"]
+    );
+    assert_eq!(
+        new.only_changed(&changes)
+            .users
+            .iter()
+            .map(|user| &user.name)
+            .collect_vec(),
+        ["bob", "dave"]
+    );
+
+    let revoke = changes.revoke_removed(&PlayOptions::default());
+    assert_eq!(revoke.len(), 1);
+    assert_eq!(revoke[0].hosts, "db");
+    assert_eq!(revoke[0].tasks.len(), 1);
+    assert_eq!(
+        revoke[0].tasks[0].module.params["state"],
+        Value::from("absent")
+    );
+
+    assert_eq!(new.diff(&new), Default::default());
+}
+
+#[test]
+fn test_stable_playbook() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let serialize = || serialize_plays(&conf.create_accounts(&PlayOptions::default()), None);
+
+    // Each run builds new params maps, which iterate in a different order.
+    let first = serialize().unwrap();
+    for _ in 0..5 {
+        assert_eq!(serialize().unwrap(), first);
+    }
+    assert!(first.contains(
+        "  - name: Authorize public key.\n    ansible.posix.authorized_key:\n      exclusive:"
+    ));
+}
+
+#[test]
+fn test_transform_playbook() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let plays = conf.create_accounts(&PlayOptions::default());
+
+    let untransformed = serialize_plays(&plays, None).unwrap();
+    let identity = serialize_plays(&plays, Some("cat")).unwrap();
+    assert_eq!(untransformed, identity);
+
+    let renamed = serialize_plays(&plays, Some("sed 's/Create groups./Make groups./'")).unwrap();
+    assert!(renamed.contains("name: Make groups."));
+
+    assert!(serialize_plays(&plays, Some("exit 3")).is_err());
+
+    let failing = RunOptions {
+        transform: Some("exit 3".to_string()),
+        ..Default::default()
+    };
+    let is_transform_error =
+        |result: anyhow::Error| result.downcast_ref::<TransformError>().is_some();
+    assert!(is_transform_error(
+        run_plays(&plays, &[], &failing).unwrap_err()
+    ));
+    assert!(is_transform_error(
+        run_plays_json(&plays, &[], &failing).unwrap_err()
+    ));
+    assert!(is_transform_error(
+        test_plays(&plays, "hosts", &[], &failing).unwrap_err()
+    ));
+}
+
+#[test]
+fn test_revoked_keys() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+
+    let opts = CheckOptions {
+        revoked: HashSet::from([keys::fingerprint(&conf.users[1].pubkeys[0].key).unwrap()]),
+        ..Default::default()
+    };
+    let err = conf.check(&opts).unwrap_err();
+    assert!(err.message.contains("nopasspetey"));
+}
+
+#[test]
+fn test_validate_tasks_unchanged() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let read_only = [
+        "ansible.builtin.set_fact",
+        "ansible.builtin.getent",
+        "ansible.builtin.slurp",
+    ];
+    let tasks = AnsiblePlay::validate(&conf, &PlayOptions::default(), None)
+        .into_iter()
+        .flat_map(|play| play.tasks)
+        .filter(|task| read_only.contains(&task.module.name))
+        .collect::<Vec<_>>();
+
+    assert!(!tasks.is_empty());
+    for task in tasks {
+        assert_eq!(task.params.get("changed_when"), Some(&Value::Bool(false)));
+    }
+}
+
+#[test]
+fn test_validation_artifact() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("drift.json");
+
+    let plays = AnsiblePlay::validate(&conf, &PlayOptions::default(), Some(&path));
+    let tasks = &plays.last().unwrap().tasks;
+    let write = tasks
+        .iter()
+        .position(|task| task.module.name == "ansible.builtin.copy");
+    let fail = tasks.iter().position(|task| task.name == EXTRA_KEYS_TASK);
+    // Drift fails the host, so the artifact must be written first.
+    assert!(write.unwrap() < fail.unwrap());
+    assert_eq!(
+        tasks[write.unwrap()].module.params.get("dest"),
+        Some(&Value::from(path.display().to_string()))
+    );
+
+    fs::write(
+        &path,
+        r#"{
+            "web2": {"extra": {}, "missing": {}},
+            "web1": {
+                "extra": {"carol": ["ssh-ed25519 AAAAc"], "bob": ["ssh-ed25519 AAAAb"]},
+                "missing": {"bob": ["ssh-ed25519 AAAAnew"]}
+            }
+        }"#,
+    )
+    .unwrap();
+    let reports = read_validation_artifact(&path).unwrap();
+    assert_eq!(
+        reports
+            .iter()
+            .map(|report| (report.host.as_str(), report.user.as_str()))
+            .collect::<Vec<_>>(),
+        vec![("web1", "bob"), ("web1", "carol")]
+    );
+    assert_eq!(reports[0].missing_keys, vec!["ssh-ed25519 AAAAnew"]);
+    assert_eq!(reports[1].extra_keys, vec!["ssh-ed25519 AAAAc"]);
+}
+
+#[test]
+fn test_junit_report() {
+    let output: serde_json::Value = serde_json::json!({
+        "plays": [{
+            "tasks": [{
+                "task": {"name": EXTRA_KEYS_TASK},
+                "hosts": {
+                    "web1": {"results": [
+                        {"item": {"key": "bob", "value": ["ssh-ed25519 AAAA bob@<laptop>"]}}
+                    ]},
+                    "web2": {"results": []},
+                    "web3": {"results": []}
+                }
+            }, {
+                "task": {"name": MISSING_KEYS_TASK},
+                "hosts": {
+                    "web1": {"results": [
+                        {"item": {"key": "bob", "value": ["ssh-ed25519 AAAAnew"]}}
+                    ]},
+                    "web2": {"results": []},
+                    "web3": {"results": [
+                        {"item": {"key": "alice", "value": ["ssh-ed25519 AAAAalice"]}}
+                    ]}
+                }
+            }]
+        }],
+        "stats": {"web1": {}, "web2": {}, "web3": {}}
+    });
+
+    let (hosts, reports) = report::parse_validate_output(&output);
+    assert_eq!(hosts, vec!["web1", "web2", "web3"]);
+    assert_eq!(
+        reports,
+        vec![
+            ValidationReport {
+                host: "web1".to_string(),
+                user: "bob".to_string(),
+                extra_keys: vec!["ssh-ed25519 AAAA bob@<laptop>".to_string()],
+                missing_keys: vec!["ssh-ed25519 AAAAnew".to_string()],
+            },
+            ValidationReport {
+                host: "web3".to_string(),
+                user: "alice".to_string(),
+                extra_keys: vec![],
+                missing_keys: vec!["ssh-ed25519 AAAAalice".to_string()],
+            }
+        ]
+    );
+
+    let xml = report::junit(&hosts, &reports);
+    assert!(xml.contains("tests=\"3\" failures=\"2\""));
+    assert!(xml.contains("alice has missing keys:"));
+    assert!(xml.contains("<testcase name=\"web2\"/>"));
+    assert!(xml.contains("bob@&lt;laptop&gt;"));
+}
+
+#[test]
+fn test_umask() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    conf.users[0].access[0].umask = Some("0077".to_string());
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    let task = play.tasks.last().unwrap();
+    assert_eq!(task.module.params.get("umask"), Some(&Value::from("0077")));
+
+    conf.users[0].access[0].umask = Some("0o77".to_string());
+    assert!(conf.check(&CheckOptions::default()).is_err());
+}
+
+#[test]
+fn test_password_hash() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let task = AnsiblePlay::create_user(&conf.users[0])[0]
+        .tasks
+        .pop()
+        .unwrap();
+    assert_eq!(task.module.params.get("password"), Some(&Value::from("*")));
+    assert_eq!(task.params.get("no_log"), None);
+
+    let hash = "$6$salt$hashedpassword";
+    conf.users[0].access[0].password_hash = Some(hash.to_string());
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    let task = AnsiblePlay::create_user(&conf.users[0])[0]
+        .tasks
+        .pop()
+        .unwrap();
+    assert_eq!(task.module.params.get("password"), Some(&Value::from(hash)));
+    assert_eq!(task.params.get("no_log"), Some(&Value::Bool(true)));
+
+    conf.users[0].access[0].password_hash = Some("hunter2".to_string());
+    let err = conf.check(&CheckOptions::default()).unwrap_err();
+    assert!(err.message.contains("invalid password_hash"));
+    assert!(!err.message.contains("hunter2"));
+}
+
+#[test]
+fn test_authorized_keys_path() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    conf.users[0].access[0].authorized_keys_path = Some("/etc/ssh/authorized_keys/%u".to_string());
+
+    let play = &AnsiblePlay::authorize_keys(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks[0].module.params.get("path"),
+        Some(&Value::from("/etc/ssh/authorized_keys/sudoerjoe"))
+    );
+    assert_eq!(
+        play.tasks[0].module.params.get("manage_dir"),
+        Some(&Value::from("false"))
+    );
+    let play = &AnsiblePlay::authorize_keys(&conf.users[1])[0];
+    assert_eq!(play.tasks[0].module.params.get("path"), None);
+
+    // Validate reads the keys from the same path.
+    let facts = AnsiblePlay::set_desired_pubkey_facts(&conf);
+    assert_eq!(facts[0].tasks.len(), 2);
+    assert!(facts[0].tasks[1].module.params["authorized_keys_paths"]
+        .as_str()
+        .unwrap()
+        .contains("\"/etc/ssh/authorized_keys/sudoerjoe\""));
+    assert_eq!(facts[1].tasks.len(), 1);
+}
+
+#[test]
+fn test_config_parse_error() {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(b"- name: x\n  pubkeys: []\n  access:\n    - hosts: a\n      role: admin\n")
+        .unwrap();
+    let path = file.path().to_str().unwrap();
+
+    let err = read_config(path).unwrap_err();
+    assert!(err
+        .message
+        .starts_with(&format!("failed to parse {path} at line 5, column 13: ")));
+    assert!(!err.message.ends_with("column 13"));
+
+    assert!(read_config("test/missing.yml").is_err());
+
+    let err = parse_config("stdin", "\n  \n").unwrap_err();
+    assert_eq!(err.message, "stdin is empty");
+}
+
+#[test]
+fn test_run_once_sudoers() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let play = &conf.bootstrap(&PlayOptions {
+        run_once: true,
+        ..Default::default()
+    })[0];
+    let render = play
+        .tasks
+        .iter()
+        .find(|task| task.name == "Render sudoers content.")
+        .unwrap();
+    assert_eq!(render.params.get("run_once"), Some(&Value::Bool(true)));
+
+    let copy = play
+        .tasks
+        .iter()
+        .find(|task| task.module.name == "ansible.builtin.copy")
+        .unwrap();
+    assert_eq!(copy.params.get("run_once"), None);
+    assert_eq!(
+        copy.module.params.get("content"),
+        Some(&Value::from("{{ sshman_sudoers['sshman-sudoer'] }}"))
+    );
+}
+
+#[test]
+fn test_host_access() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let pattern_hosts = HashMap::from([
+        (
+            "sydney:melbourne:&staging",
+            HashMap::from([("mel-stg".to_string(), "mel-stg".to_string())]),
+        ),
+        (
+            "melbourne",
+            HashMap::from([
+                ("mel-stg".to_string(), "mel-stg".to_string()),
+                ("mel-prd".to_string(), "mel-prd".to_string()),
+            ]),
+        ),
+        (
+            "*",
+            HashMap::from([
+                ("mel-stg".to_string(), "mel-stg".to_string()),
+                ("mel-prd".to_string(), "mel-prd".to_string()),
+            ]),
+        ),
+    ]);
+
+    let staging = conf.host_access("mel-stg", &pattern_hosts);
+    let production = conf.host_access("mel-prd", &pattern_hosts);
+
+    assert_eq!(
+        staging.difference(&production).collect::<Vec<_>>(),
+        vec![&("sudoerjoe", &Role::Sudoer)]
+    );
+    assert!(production.is_subset(&staging));
+}
+
+#[test]
+fn test_diff_report() {
+    let output: serde_json::Value = serde_json::json!({
+        "plays": [
+            {"tasks": [{
+                "task": {"name": "Create sudoer account."},
+                "hosts": {
+                    "web1": {"action": "ansible.builtin.user", "changed": true, "name": "alice"},
+                    "web2": {"action": "ansible.builtin.user", "changed": false, "name": "alice"}
+                }
+            }]},
+            {"tasks": [{
+                "task": {"name": KEY_FACTS_TASK},
+                "hosts": {
+                    "web1": {"msg": {"desired": {"alice": ["new"]}, "actual": {}}},
+                    "web2": {"msg": {
+                        "desired": {"alice": ["kept", "new"]},
+                        "actual": {"alice": ["kept", "old"], "root": ["unmanaged"]}
+                    }}
+                }
+            }]}
+        ]
+    });
+
+    let strings = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        report::parse_diff_output(&output),
+        vec![
+            UserDiff {
+                host: "web1".to_string(),
+                user: "alice".to_string(),
+                account_changed: true,
+                add: strings(&["new"]),
+                remove: vec![],
+                unchanged: vec![],
+            },
+            UserDiff {
+                host: "web2".to_string(),
+                user: "alice".to_string(),
+                account_changed: false,
+                add: strings(&["new"]),
+                remove: strings(&["old"]),
+                unchanged: strings(&["kept"]),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_disabled_role() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    conf.users[0].access[0].role = Role::Disabled;
+
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    let task = play.tasks.last().unwrap();
+    assert_eq!(
+        task.module.params.get("password_lock"),
+        Some(&Value::Bool(true))
+    );
+    assert_eq!(
+        task.module.params.get("shell"),
+        Some(&Value::from("/usr/sbin/nologin"))
+    );
+
+    let play = &AnsiblePlay::authorize_keys(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks[0].module.params.get("state"),
+        Some(&Value::from("present"))
+    );
+}
+
+#[test]
+fn test_primary_group() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let stmt = &mut conf.users[0].access[0];
+    stmt.groups = vec!["deploy".to_string()];
+    stmt.primary_group = Some("staff".to_string());
+
+    // The private group isn't created, and the primary group is checked before the account.
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks.iter().map(|task| task.name).collect::<Vec<_>>(),
+        [
+            "Create group user group.",
+            "Check primary group exists.",
+            "Create sudoer account."
+        ]
+    );
+    assert_eq!(play.tasks[1].module.params["key"], Value::from("staff"));
+    assert_eq!(play.tasks[2].module.params["group"], Value::from("staff"));
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    assert!(conf.warnings().is_empty());
+
+    // A primary group that is also an extra group is created by the play instead.
+    conf.users[0].access[0].primary_group = Some("deploy".to_string());
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert!(!play
+        .tasks
+        .iter()
+        .any(|task| task.name == "Check primary group exists."));
+
+    conf.users[0].access[0].primary_group = Some(Role::Sudoer.group().to_string());
+    assert!(conf.check(&CheckOptions::default()).is_err());
+
+    conf.users[0].access[0].role = Role::Disabled;
+    assert!(conf.warnings().iter().any(|w| w.contains("primary_group")));
+}
+
+#[test]
+fn test_remove_extra_keys() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let reports = [
+        ValidationReport {
+            host: "mel-prd".to_string(),
+            user: "sudoerjoe".to_string(),
+            extra_keys: vec![
+                "ssh-ed25519 AAAA one".to_string(),
+                "ssh-rsa BBBB two".to_string(),
+            ],
+            missing_keys: vec![],
+        },
+        ValidationReport {
+            host: "syd-prd".to_string(),
+            user: "sudoerjoe".to_string(),
+            extra_keys: vec![],
+            missing_keys: vec!["ssh-ed25519 CCCC".to_string()],
+        },
+    ];
+
+    let plays = AnsiblePlay::remove_extra_keys(&conf, &reports, &PlayOptions::default());
+    // Only the host with extra keys is changed, after the path facts are populated.
+    let fixes = plays
+        .iter()
+        .filter(|play| play.name.starts_with("Remove extra keys"))
+        .collect::<Vec<_>>();
+    assert_eq!(fixes.len(), 1);
+    assert_eq!(fixes[0].hosts, "mel-prd");
+    let params = &fixes[0].tasks[0].module.params;
+    assert_eq!(params["user"], Value::from("sudoerjoe"));
+    assert_eq!(
+        params["key"],
+        Value::from("ssh-ed25519 AAAA one\nssh-rsa BBBB two")
+    );
+    assert_eq!(params["state"], Value::from("absent"));
+    assert!(plays.len() > 1);
+
+    assert!(
+        AnsiblePlay::remove_extra_keys(&conf, &reports[1..], &PlayOptions::default()).is_empty()
+    );
+}
+
+#[test]
+fn test_statement_condition() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let condition = "ansible_os_family == 'Debian'";
+    for user in &mut conf.users {
+        user.access[0].when = Some(condition.to_string());
+    }
+
+    let plays = conf.create_accounts(&PlayOptions::default());
+    let tasks = plays
+        .iter()
+        .filter(|play| {
+            play.name.starts_with("Create accounts") || play.name.starts_with("Authorize")
+        })
+        .flat_map(|play| &play.tasks)
+        .collect_vec();
+    assert!(!tasks.is_empty());
+    assert!(tasks.iter().all(|task| task.params["when"] == condition));
+
+    // Blocked users' keys are still removed ignoring errors, under the condition.
+    let blocked = &AnsiblePlay::authorize_keys(&conf.users[3])[0].tasks[0];
+    assert_eq!(blocked.params["ignore_errors"], Value::Bool(true));
+    assert_eq!(blocked.params["when"], Value::from(condition));
+
+    // An existing condition is kept alongside the statement's.
+    let task = AnsiblePlay::authorize_keys(&conf.users[0])
+        .remove(0)
+        .tasks
+        .remove(0)
+        .when("inventory_hostname != 'web-1'");
+    assert_eq!(
+        task.params["when"],
+        Value::from(vec![condition, "inventory_hostname != 'web-1'"])
+    );
+}
+
+#[test]
+fn test_absent_role() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    conf.users[0].access.truncate(1);
+    conf.users[0].access[0].role = "absent".parse().unwrap();
+
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks.iter().map(|task| task.name).collect::<Vec<_>>(),
+        ["Remove public keys.", "Remove account."]
+    );
+    let account = &play.tasks[1].module.params;
+    assert_eq!(account.get("state"), Some(&Value::from("absent")));
+    assert_eq!(account.get("remove"), Some(&Value::Bool(false)));
+    assert!(AnsiblePlay::authorize_keys(&conf.users[0]).is_empty());
+    assert!(!conf.warnings().iter().any(|w| w.contains("remove_home")));
+
+    // Home directories are only removed when asked for.
+    conf.users[0].access[0].remove_home = Some(true);
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks[1].module.params.get("remove"),
+        Some(&Value::Bool(true))
+    );
+
+    conf.users[0].access[0].role = Role::Sudoer;
+    assert!(conf.warnings().iter().any(|w| w.contains("remove_home")));
+}
+
+#[test]
+fn test_seuser() {
+    let mut user: SSHUser = serde_yaml::from_str(
+        "
+name: admin
+access:
+  - hosts: all
+    role: sudoer
+    seuser: sysadm_u
+",
+    )
+    .unwrap();
+
+    let play = &AnsiblePlay::create_user(&user)[0];
+    let account = &play.tasks[play.tasks.len() - 2];
+    assert_eq!(
+        account.module.params.get("seuser"),
+        Some(&Value::from("sysadm_u"))
+    );
+    let mapping = play.tasks.last().unwrap();
+    assert_eq!(mapping.module.name, "community.general.selogin");
+    assert_eq!(
+        mapping.module.params.get("login"),
+        Some(&Value::from("admin"))
+    );
+
+    user.access[0].role = Role::Blocked;
+    let play = &AnsiblePlay::create_user(&user)[0];
+    assert!(play
+        .tasks
+        .iter()
+        .all(|task| task.module.name != "community.general.selogin"));
+}
+
+#[test]
+fn test_key_states() {
+    let user: SSHUser = serde_yaml::from_str(
+        "
+name: rotating
+pubkeys:
+  - ssh-ed25519 AAAAnew new@laptop
+  - key: ssh-ed25519 AAAAkept kept@laptop
+    state: present
+  - key: ssh-ed25519 AAAAold old@laptop
+    state: absent
+  - ssh-ed25519 AAAAnew new@laptop
+access:
+  - hosts: all
+    role: sudoer
+",
+    )
+    .unwrap();
+
+    let play = &AnsiblePlay::authorize_keys(&user)[0];
+    assert_eq!(play.tasks.len(), 2);
+    assert_eq!(
+        play.tasks[0].module.params.get("key"),
+        Some(&Value::from(
+            "ssh-ed25519 AAAAkept kept@laptop\nssh-ed25519 AAAAnew new@laptop"
+        ))
+    );
+    assert_eq!(
+        play.tasks[1].module.params.get("key"),
+        Some(&Value::from("ssh-ed25519 AAAAold old@laptop"))
+    );
+    assert_eq!(
+        play.tasks[1].module.params.get("state"),
+        Some(&Value::from("absent"))
+    );
+    assert_eq!(play.tasks[1].module.params.get("exclusive"), None);
+
+    // Absent keys are still removed when other keys are left alone.
+    let mut user = user;
+    user.access[0].exclusive = Some(false);
+    let play = &AnsiblePlay::authorize_keys(&user)[0];
+    assert_eq!(
+        play.tasks[0].module.params.get("exclusive"),
+        Some(&Value::from("false"))
+    );
+    assert_eq!(
+        play.tasks[1].module.params.get("state"),
+        Some(&Value::from("absent"))
+    );
+}
+
+#[test]
+fn test_inventory_patterns() {
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+    let hosts = |pattern: &str| {
+        let mut hosts = inventory
+            .get_pattern_hosts(pattern)
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        hosts.sort();
+        hosts
+    };
+
+    assert_eq!(
+        hosts("sydney:melbourne:&staging"),
+        vec!["mel-stg", "syd-stg"]
+    );
+    assert_eq!(hosts("melbourne:!staging"), vec!["mel-prd"]);
+    assert_eq!(hosts("bastion,syd-prd"), vec!["bastion", "syd-prd"]);
+    assert_eq!(hosts("*").len(), 5);
+    assert!(inventory.get_pattern_hosts("perth").is_err());
+
+    assert_eq!(hosts("ungrouped"), vec!["bastion"]);
+    assert_eq!(
+        hosts("all:!melbourne:!ungrouped"),
+        vec!["syd-prd", "syd-stg"]
+    );
+    assert_eq!(hosts("all:&staging:!sydney"), vec!["mel-stg"]);
+    assert_eq!(
+        hosts("ungrouped:staging"),
+        vec!["bastion", "mel-stg", "syd-stg"]
+    );
+
+    assert_eq!(hosts("*-stg"), vec!["mel-stg", "syd-stg"]);
+    assert_eq!(hosts("syd*:&staging"), vec!["syd-stg"]);
+    assert_eq!(hosts("???-prd"), vec!["mel-prd", "syd-prd"]);
+    assert_eq!(hosts("melbourne:!*-stg"), vec!["mel-prd"]);
+    assert!(hosts("perth*").is_empty());
+
+    assert_eq!(hosts("~(mel|syd)-stg"), vec!["mel-stg", "syd-stg"]);
+    assert_eq!(hosts("~.*prd:!melbourne"), vec!["syd-prd"]);
+    assert_eq!(hosts("~s"), vec!["syd-prd", "syd-stg"]);
+    assert!(hosts("~stg$").is_empty());
+    assert!(hosts("~[unclosed").is_empty());
+    assert_eq!(hosts("!melbourne:!sydney"), vec!["bastion"]);
+
+    let jobs = NonZeroUsize::new(2).unwrap();
+    let resolved = inventory
+        .list_patterns_hosts(["melbourne", "staging", "melbourne", "*-prd"], jobs)
+        .unwrap();
+    assert_eq!(resolved.len(), 3);
+    for pattern in ["melbourne", "staging", "*-prd"] {
+        assert_eq!(resolved[pattern], inventory.list_hosts(pattern).unwrap());
+    }
+    assert!(inventory
+        .list_patterns_hosts(["melbourne", "perth", "staging"], jobs)
+        .is_err());
+
+    let canaries = Inventory::from_yaml(
+        "all:\n  children:\n    prod:\n      hosts:\n        web1:\n        web2-canary:\n        db1:\n    \
+        dev:\n      hosts:\n        web3:\n        db2-canary:\n",
+    )
+    .unwrap();
+    let canary_hosts = |pattern: &str| {
+        canaries
+            .get_pattern_hosts(pattern)
+            .unwrap()
+            .into_iter()
+            .sorted()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(canary_hosts("web*:&prod"), vec!["web1", "web2-canary"]);
+    assert_eq!(canary_hosts("!*-canary"), vec!["db1", "web1", "web3"]);
+
+    let listed = Inventory::read("test/inventory_nested.yml").unwrap();
+    let listed_hosts = |pattern: &str| {
+        listed
+            .get_pattern_hosts(pattern)
+            .unwrap()
+            .into_iter()
+            .sorted()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        listed_hosts("syd-web*:&production"),
+        vec!["syd-web1", "syd-web2"]
+    );
+    assert_eq!(listed_hosts("all:!*-web?"), vec!["bastion", "syd-db1"]);
+    assert_eq!(listed_hosts("!*-web?"), vec!["bastion", "syd-db1"]);
+    assert_eq!(
+        listed_hosts("&sydney"),
+        vec!["syd-db1", "syd-web1", "syd-web2"]
+    );
+    assert_eq!(
+        listed.get_pattern_hosts("ungrouped").unwrap(),
+        HashSet::from(["bastion".to_string()])
+    );
+}
+
+#[test]
+fn test_check_patterns() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let opts = CheckOptions {
+        inventory: Some(Inventory::read("test/inventory.yml").unwrap()),
+        ..Default::default()
+    };
+    assert!(conf.check(&opts).is_ok());
+
+    conf.users[1].access[0].hosts = "melbourne:&sydney".to_string();
+    conf.users[2].access[0].hosts = "perth".to_string();
+    let err = conf.check(&opts).unwrap_err();
+    assert!(err
+        .message
+        .contains("nopasspetey has host pattern melbourne:&sydney which matches no hosts"));
+    assert!(err
+        .message
+        .contains("superuser has host pattern perth which can't be resolved"));
+
+    let opts = CheckOptions {
+        allow_empty_patterns: true,
+        ..opts
+    };
+    let err = conf.check(&opts).unwrap_err();
+    assert!(!err.message.contains("matches no hosts"));
+    assert!(err.message.contains("perth which can't be resolved"));
+}
+
+#[test]
+fn test_multiple_superusers() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    conf.users[1].access[0].role = Role::SuperUser;
+    // Overlaps the superuser's '*' only once the patterns are resolved.
+    let opts = CheckOptions {
+        inventory: Some(Inventory::read("test/inventory.yml").unwrap()),
+        ..Default::default()
+    };
+    let err = conf.check(&opts).unwrap_err();
+    assert!(err.message.contains(
+        "users nopasspetey, superuser are all superusers on mel-prd, mel-stg, which would"
+    ));
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+
+    conf.users[1].access[0].hosts = "*".to_string();
+    let err = conf.check(&CheckOptions::default()).unwrap_err();
+    assert!(err.message.contains("on host pattern *,"));
+
+    let opts = CheckOptions {
+        allow_multiple_superusers: true,
+        ..opts
+    };
+    assert!(conf.check(&opts).is_ok());
+}
+
+#[test]
+fn test_inventory_hostnames() {
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+
+    assert_eq!(
+        inventory.list_hosts("melbourne:!mel-prd").unwrap(),
+        HashMap::from([("mel-stg".to_string(), "10.0.1.10".to_string())])
+    );
+    assert_eq!(
+        inventory.list_hosts("syd-prd").unwrap(),
+        HashMap::from([("syd-prd".to_string(), "syd-prd".to_string())])
+    );
+
+    let legacy =
+        Inventory::from_yaml("all:\n  hosts:\n    web:\n      ansible_ssh_host: 10.0.2.5\n")
+            .unwrap();
+    assert_eq!(
+        legacy.list_hosts("web").unwrap(),
+        HashMap::from([("web".to_string(), "10.0.2.5".to_string())])
+    );
+
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let pattern_hosts = conf.resolve_patterns(&inventory).unwrap();
+    assert!(conf
+        .host_access("mel-stg", &pattern_hosts)
+        .contains(&("sudoerjoe", &Role::Sudoer)));
+}
+
+#[test]
+fn test_expand_vars() {
+    let mut conf = parse_config(
+        "test",
+        r#"
+- name: alice
+  comment: owned by ${TEAM}
+  access:
+    - hosts: ${ENV}_web:!${ENV}_db
+      role: sudoer
+      groups: [deploy, "${TEAM:-ops}"]
+      home: /srv/$${HOME}
+      password_hash: $6$salt$hash
+      seuser: ${SEUSER:-user_u}
+"#,
+    )
+    .unwrap();
+    let lookup = |name: &str| match name {
+        "ENV" => Some("prd".to_string()),
+        "TEAM" => Some("platform".to_string()),
+        _ => None,
+    };
+    conf.expand_vars(lookup).unwrap();
+
+    let user = &conf.users[0];
+    let stmt = &user.access[0];
+    assert_eq!(user.comment.as_deref(), Some("owned by platform"));
+    assert_eq!(stmt.hosts, "prd_web:!prd_db");
+    assert_eq!(stmt.groups, ["deploy", "platform"]);
+    assert_eq!(stmt.home.as_deref(), Some("/srv/${HOME}"));
+    assert_eq!(stmt.password_hash.as_deref(), Some("$6$salt$hash"));
+    assert_eq!(stmt.seuser.as_deref(), Some("user_u"));
+
+    conf.users[0].access[0].shell = Some("${SHELL_PATH}".to_string());
+    let err = conf.expand_vars(lookup).unwrap_err();
+    assert!(err
+        .message
+        .contains("user alice references undefined variable SHELL_PATH"));
+
+    conf.users[0].access[0].shell = Some("${SHELL".to_string());
+    assert!(conf.expand_vars(lookup).is_err());
+}
+
+#[test]
+fn test_role_aliases() {
+    for (alias, role) in [
+        ("locked", Role::Blocked),
+        ("sudo", Role::Sudoer),
+        ("nopasswd", Role::Nopass),
+        ("root", Role::SuperUser),
+    ] {
+        let stmt: AccessStmt = serde_yaml::from_str(&format!("hosts: all\nrole: {alias}")).unwrap();
+        assert_eq!(stmt.role, role);
+        // Aliases are written back as the role name.
+        assert!(!serde_yaml::to_string(&stmt)
+            .unwrap()
+            .contains(&format!("role: {alias}\n")));
+    }
+
+    let err = serde_yaml::from_str::<AccessStmt>("hosts: all\nrole: admin").unwrap_err();
+    assert!(err.to_string().contains(
+        "Invalid role admin; expected blocked, disabled, sudoer, nopass, superuser or absent"
+    ));
+}
+
+#[test]
+fn test_schema() {
+    let schema = serde_json::to_value(schemars::schema_for!(SSHConfig)).unwrap();
+
+    // Both shapes of config are accepted.
+    assert_eq!(schema["anyOf"][0]["type"], "array");
+    assert_eq!(schema["anyOf"][1]["$ref"], "#/$defs/ConfigMap");
+
+    let defs = &schema["$defs"];
+    assert_eq!(defs["Role"]["enum"][0], "blocked");
+    assert!(defs["Role"]["enum"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|role| role.as_str().unwrap().parse::<Role>().is_ok()));
+    assert_eq!(
+        defs["AccessStmt"]["required"],
+        serde_json::json!(["hosts", "role"])
+    );
+    assert!(defs["AccessStmt"]["properties"]["become"].is_object());
+    assert_eq!(defs["ConfigMap"]["additionalProperties"], false);
+    // Keys can be written as a plain string or a mapping.
+    assert_eq!(defs["PubKey"]["anyOf"][0]["type"], "string");
+}
+
+#[test]
+fn test_known_hosts() {
+    let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc";
+    let conf = parse_config(
+        "test",
+        &format!(
+            r#"
+known_hosts:
+  - name: git.example.com
+    key: {key}
+  - name: bastion.example.com
+    key: {key}
+    hosts: web
+users: []
+"#
+        ),
+    )
+    .unwrap();
+    conf.validate_keys().unwrap();
+
+    let plays = AnsiblePlay::manage_known_hosts(&conf);
+    assert_eq!(
+        plays.iter().map(|play| play.hosts.as_str()).collect_vec(),
+        ["all", "web"]
+    );
+    let expected: Value = serde_yaml::from_str(&format!(
+        r#"
+name: Trust host key.
+ansible.builtin.known_hosts:
+  key: git.example.com {key}
+  name: git.example.com
+  path: /etc/ssh/ssh_known_hosts
+  state: present
+tags: [known_hosts]
+"#
+    ))
+    .unwrap();
+    assert_eq!(serde_yaml::to_value(&plays[0].tasks[0]).unwrap(), expected);
+    assert!(conf
+        .create_accounts(&PlayOptions::default())
+        .iter()
+        .any(|play| play.name == "Trust host keys on web."));
+
+    // The config round trips with its known hosts.
+    let written = serde_yaml::to_string(&conf).unwrap();
+    assert_eq!(
+        parse_config("written", &written).unwrap().known_hosts,
+        conf.known_hosts
+    );
+
+    let mut conf = conf;
+    conf.known_hosts[0].key = "ssh-ed25519".to_string();
+    let err = conf.validate_keys().unwrap_err();
+    assert!(err
+        .message
+        .contains("known host git.example.com has invalid key"));
+}
+
+#[test]
+fn test_identity_access() {
+    let mut conf = parse_config(
+        "test",
+        r#"
+- name: alice
+  pubkeys: []
+  access:
+    - hosts: db
+      role: disabled
+- name: bob
+  pubkeys: []
+  access: []
+"#,
+    )
+    .unwrap();
+    let groups: GroupAccess = serde_yaml::from_str(
+        r#"
+admins: [{hosts: all, role: sudoer}]
+oncall: [{hosts: web, role: nopass}, {hosts: all, role: sudoer}]
+"#,
+    )
+    .unwrap();
+    let memberships: Memberships =
+        serde_json::from_str(r#"{"alice": ["admins", "oncall", "staff"], "carol": ["admins"]}"#)
+            .unwrap();
+
+    let unknown = identity::derive_access(&mut conf.users, &groups, &memberships);
+    assert_eq!(unknown, ["carol"]);
+    assert_eq!(
+        conf.users[0]
+            .access
+            .iter()
+            .map(|stmt| (stmt.hosts.as_str(), &stmt.role))
+            .collect_vec(),
+        [
+            ("db", &Role::Disabled),
+            ("all", &Role::Sudoer),
+            ("web", &Role::Nopass)
+        ]
+    );
+    assert!(conf.users[1].access.is_empty());
+    assert!(conf
+        .create_accounts(&PlayOptions::default())
+        .iter()
+        .any(|play| play.name == "Create accounts for alice." && play.hosts == "web"));
+}
+
+#[test]
+fn test_uncovered_hosts() {
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(conf.uncovered_hosts(&inventory).is_empty());
+
+    for user in &mut conf.users {
+        user.access.retain(|stmt| stmt.hosts != "*");
+    }
+    assert_eq!(
+        conf.uncovered_hosts(&inventory),
+        HashSet::from(["bastion".to_string(), "syd-prd".to_string()])
+    );
+}
+
+#[test]
+fn test_key_groups() {
+    let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc";
+    let mut conf = parse_config(
+        "test",
+        &format!(
+            r#"
+key_groups:
+  break-glass: ["{key}"]
+users:
+  - name: alice
+    pubkeys:
+      - "@break-glass"
+      - key: "@break-glass"
+        options: no-pty
+    access:
+      - hosts: all
+        role: sudoer
+"#
+        ),
+    )
+    .unwrap();
+    conf.expand_key_groups().unwrap();
+    let pubkeys = &conf.users[0].pubkeys;
+    assert_eq!(pubkeys.len(), 2);
+    assert!(pubkeys.iter().all(|pubkey| pubkey.key == key));
+    assert_eq!(pubkeys[1].options.as_deref(), Some("no-pty"));
+
+    conf.users[0].pubkeys[0].key = "@missing".to_string();
+    let err = conf.expand_key_groups().unwrap_err();
+    assert!(err
+        .message
+        .contains("user alice references undefined key group missing"));
+}
+
+#[test]
+fn test_statement_keys() {
+    let jump = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc";
+    let direct = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIARL4yy4L4t/nzjMHyBjeJJeXjb4kncXYOtS+w9I4Wrj";
+    let conf = parse_config(
+        "test",
+        &format!(
+            r#"
+- name: alice
+  pubkeys: ["{direct}"]
+  access:
+    - hosts: bastion
+      role: disabled
+      pubkeys: ["{jump}"]
+    - hosts: web
+      role: sudoer
+"#
+        ),
+    )
+    .unwrap();
+    conf.validate_keys().unwrap();
+
+    let user = &conf.users[0];
+    let plays = AnsiblePlay::authorize_keys(user);
+    assert_eq!(plays[0].hosts, "bastion");
+    assert_eq!(plays[0].tasks[0].module.params["key"], Value::from(jump));
+    assert_eq!(plays[1].tasks[0].module.params["key"], Value::from(direct));
+    assert_eq!(user.present_keys().count(), 2);
+
+    let report = conf.keys_report(None).unwrap();
+    assert_eq!(
+        report,
+        format!("# alice\n# bastion as disabled user\n{jump}\n# web as sudo user\n{direct}\n\n")
+    );
+}
+
+#[test]
+fn test_graph() {
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let dot = graph::to_dot(&conf, &conf.resolve_patterns(&inventory).unwrap());
+
+    assert!(dot.starts_with("digraph access {"));
+    assert!(dot.contains("\"user:sudoerjoe\" [label=\"sudoerjoe\", shape=box];"));
+    assert!(dot.contains("\"host:mel-stg\" [label=\"mel-stg\", shape=ellipse];"));
+    assert!(
+        dot.contains("\"user:sudoerjoe\" -> \"host:mel-stg\" [label=\"sudo user\", color=blue];")
+    );
+    // Each edge is only drawn once, even if several statements grant it.
+    assert_eq!(dot.lines().count(), dot.lines().unique().count());
+}
+
+#[test]
+fn test_validate_keys() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(conf.validate_keys().is_ok());
+
+    let key = conf.users[0].pubkeys[0].key.clone();
+    let parsed = keys::parse_key(&key).unwrap();
+    assert_eq!(parsed.key_type, "ssh-ed25519");
+    assert_eq!(parsed.comment, Some("sudoerjoe@mydesktop"));
+
+    let restricted = format!("from=\"10.0.0.1\",command=\"echo hi there\",no-pty {key}");
+    let parsed = keys::parse_key(&restricted).unwrap();
+    assert_eq!(
+        parsed.options,
+        Some("from=\"10.0.0.1\",command=\"echo hi there\",no-pty")
+    );
+    assert_eq!(keys::fingerprint(&restricted), keys::fingerprint(&key));
+
+    conf.users[1].pubkeys = vec![];
+    conf.users[0].pubkeys[0].key = key[..key.len() - 30].to_string();
+    conf.users[2].pubkeys[0].key = "ssh-foo AAAA".to_string();
+    let err = conf.validate_keys().unwrap_err();
+    assert!(err.message.contains("user sudoerjoe has invalid key"));
+    assert!(err
+        .message
+        .contains("user superuser has invalid key (no recognized key type)"));
+    assert!(!err.message.contains("nopasspetey"));
+}
+
+#[test]
+fn test_key_options() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let play = &AnsiblePlay::authorize_keys(&conf.users[0])[0];
+    assert_eq!(play.tasks[0].module.params.get("key_options"), None);
+
+    conf.users[0].access[0].key_options = Some("no-pty,from=\"10.0.0.0/8\"".to_string());
+    let play = &AnsiblePlay::authorize_keys(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks[0].module.params.get("key_options"),
+        Some(&Value::from("no-pty,from=\"10.0.0.0/8\""))
+    );
+}
+
+#[test]
+fn test_local_accounts() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    conf.users[0].access[0].local = Some(true);
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks.last().unwrap().module.params.get("local"),
+        Some(&Value::Bool(true))
+    );
+
+    conf.users[3].access[0].local = Some(true);
+    assert!(conf.check(&CheckOptions::default()).is_err());
+}
+
+#[test]
+fn test_account_expiry() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    conf.users[0].access[0].expires = Some("2024-02-29".to_string());
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    assert!(conf.warnings().is_empty());
+    let play = &AnsiblePlay::create_user(&conf.users[0])[0];
+    assert_eq!(
+        play.tasks.last().unwrap().module.params.get("expires"),
+        Some(&Value::from(1709164800))
+    );
+
+    for invalid in ["2023-02-29", "2024-13-01", "24-01-01", "2024-1-01", "soon"] {
+        conf.users[0].access[0].expires = Some(invalid.to_string());
+        let err = conf.check(&CheckOptions::default()).unwrap_err();
+        assert!(err.message.contains("invalid expiry date"));
+    }
+
+    conf.users[0].access[0].expires = None;
+    conf.users[3].access[0].expires = Some("2024-01-01".to_string());
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    assert_eq!(conf.warnings().len(), 1);
+}
+
+#[test]
+fn test_display_json() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+
+    let jobs = NonZeroUsize::new(2).unwrap();
+    let json = conf.display_json(Some(&inventory), jobs).unwrap();
+    assert_eq!(
+        json["sudoerjoe"][0],
+        serde_json::json!({
+            "hosts_pattern": "sydney:melbourne:&staging",
+            "role": "sudoer",
+            "groups": [],
+            "seuser": null,
+            "resolved_hosts": ["mel-stg", "syd-stg"],
+        })
+    );
+
+    // The same report is produced however many patterns are resolved at once.
+    assert_eq!(
+        conf.display_json(Some(&inventory), NonZeroUsize::MIN)
+            .unwrap(),
+        json
+    );
+
+    let unresolved = conf.display_json(None, jobs).unwrap();
+    assert!(unresolved["nopasspetey"][0]["resolved_hosts"].is_null());
+}
+
+#[test]
+fn test_lint() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(conf.lint(None).is_empty());
+
+    conf.users[0].pubkeys.clear();
+    let findings = conf.lint(None);
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].severity, Severity::Warning);
+
+    let mut conflicting = conf.users[0].access[0].clone();
+    conflicting.role = Role::Blocked;
+    conf.users[0].access.push(conflicting);
+    conf.users.push(conf.users[1].clone());
+    let errors = conf
+        .lint(None)
+        .into_iter()
+        .filter(|f| f.severity == Severity::Error)
+        .map(|f| f.message)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        vec![
+            "user nopasspetey is defined 2 times",
+            "user sudoerjoe is both a sudo user and a blocked user on host pattern sydney:melbourne:&staging",
+        ]
+    );
+}
+
+#[test]
+fn test_lint_overlapping_roles() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+    assert!(conf.lint(Some(&inventory)).is_empty());
+
+    let mut blocked = conf.users[1].access[0].clone();
+    blocked.hosts = "staging".to_string();
+    blocked.role = Role::Blocked;
+    conf.users[1].access.push(blocked.clone());
+    assert_eq!(
+        conf.lint(Some(&inventory))
+            .into_iter()
+            .map(|f| f.message)
+            .collect::<Vec<_>>(),
+        vec![
+            "user nopasspetey is a passwordless sudo user on host pattern melbourne \
+            and a blocked user on host pattern staging, which overlap on mel-stg"
+        ]
+    );
+    // Without an inventory only identical patterns can be compared.
+    assert!(conf.lint(None).is_empty());
+
+    blocked.hosts = "sydney".to_string();
+    conf.users[1].access[1] = blocked;
+    assert!(conf.lint(Some(&inventory)).is_empty());
+}
+
+#[test]
+fn test_sudoers_defaults() {
+    let users = fs::read_to_string("test/config.yml").unwrap();
+    let conf: SSHConfig = serde_yaml::from_str(&format!(
+        "sudoers_defaults:\n  - timestamp_timeout=5\nusers:\n{}",
+        users
+            .lines()
+            .filter(|line| *line != "---")
+            .map(|line| format!("  {line}\n"))
+            .join("")
+    ))
+    .unwrap();
+    assert_eq!(conf.users.len(), 4);
+
+    let play = &conf.bootstrap(&PlayOptions::default())[0];
+    let copy = play
+        .tasks
+        .iter()
+        .find(|task| task.module.name == "ansible.builtin.copy")
+        .unwrap();
+    assert_eq!(
+        copy.module.params.get("content"),
+        Some(&Value::from(
+            "%sshman-sudoer ALL=(ALL) ALL\nDefaults:%sshman-sudoer rootpw\n\
+            Defaults:%sshman-sudoer timestamp_timeout=5\n"
+        ))
+    );
+    assert_eq!(
+        copy.module.params.get("validate"),
+        Some(&Value::from("visudo -cf %s"))
+    );
+
+    assert!(serde_yaml::from_str::<SSHConfig>("users: []\nsudoer_defaults: []\n").is_err());
+
+    // The files checked locally are the ones the bootstrap play copies to hosts.
+    let copied = play
+        .tasks
+        .iter()
+        .filter(|task| task.module.name == "ansible.builtin.copy")
+        .map(|task| task.module.params["content"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    let checked = conf.sudoers_files();
+    assert_eq!(checked.len(), copied.len());
+    for ((group, content), copied) in checked.iter().zip(copied) {
+        assert_eq!(content, copied);
+        assert!(content.starts_with(&format!("%{group} ")));
+    }
+}
+
+#[test]
+fn test_become_method() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let default = serialize_plays(&conf.create_accounts(&PlayOptions::default()), None).unwrap();
+    assert!(!default.contains("become_method"));
+
+    let opts = PlayOptions {
+        become_method: Some("doas".to_string()),
+        ..Default::default()
+    };
+    for plays in [
+        conf.create_accounts(&opts),
+        AnsiblePlay::diff(&conf, &opts),
+        AnsiblePlay::validate(&conf, &opts, None),
+    ] {
+        assert!(plays
+            .iter()
+            .all(|play| play.become_method.as_deref() == Some("doas")));
+    }
+    let doas = serialize_plays(&conf.create_accounts(&opts), None).unwrap();
+    assert!(doas.contains("become_method: doas"));
+}
+
+#[test]
+fn test_connection_type() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    let default = serialize_plays(&conf.create_accounts(&PlayOptions::default()), None).unwrap();
+    assert!(!default.contains("connection:"));
+
+    let opts = PlayOptions {
+        connection: Some("local".to_string()),
+        ..Default::default()
+    };
+    let plays = conf.create_accounts(&opts);
+    assert!(plays
+        .iter()
+        .all(|play| play.connection.as_deref() == Some("local")));
+    assert!(serialize_plays(&plays, None)
+        .unwrap()
+        .contains("connection: local"));
+
+    let opts = RunOptions {
+        connection_type: Some("docker".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(opts.playbook_args(&[]), ["--connection", "docker"]);
+}
+
+#[test]
+fn test_become_user() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let default = serialize_plays(&conf.create_accounts(&PlayOptions::default()), None).unwrap();
+    assert!(!default.contains("become_user"));
+
+    let plain = AnsiblePlay::create_user(&conf.users[0]);
+    conf.users[0].access[0].become_user = Some("admin".to_string());
+    let plays = AnsiblePlay::create_user(&conf.users[0])
+        .into_iter()
+        .chain(AnsiblePlay::authorize_keys(&conf.users[0]))
+        .collect::<Vec<_>>();
+    assert!(plays
+        .iter()
+        .all(|play| play.become_user.as_deref() == Some("admin")));
+    assert!(serialize_plays(&plays, None)
+        .unwrap()
+        .contains("become_user: admin"));
+
+    // Plays for the same hosts that become different users are not combined.
+    assert_eq!(
+        AnsiblePlay::coalesce(plays.into_iter().chain(plain).collect()).len(),
+        2
+    );
+}
+
+#[test]
+fn test_become_toggle() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(AnsiblePlay::authorize_keys(&conf.users[0])
+        .iter()
+        .all(|play| play.r#become));
+
+    conf.users[0].access[0].r#become = Some(false);
+    let hosts = conf.users[0].access[0].hosts.clone();
+    let keys = AnsiblePlay::authorize_keys(&conf.users[0]);
+    assert!(!keys[0].r#become);
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+
+    // The rootless play is kept apart from the plays that need sudo, after the account is created.
+    let coalesced = conf.create_accounts(&PlayOptions {
+        coalesce: true,
+        ..Default::default()
+    });
+    let for_hosts = coalesced
+        .iter()
+        .filter(|play| play.hosts == hosts)
+        .collect::<Vec<_>>();
+    assert!(for_hosts.len() >= 2);
+    assert!(for_hosts[0].r#become);
+    let rootless = for_hosts.iter().position(|play| !play.r#become).unwrap();
+    assert!(rootless > 0);
+    assert!(for_hosts[rootless]
+        .tasks
+        .iter()
+        .all(|task| task.name.contains("public key")));
+
+    conf.users[0].access[0].become_user = Some("admin".to_string());
+    assert!(conf.check(&CheckOptions::default()).is_err());
+}
+
+#[test]
+fn test_prune_groups() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let plays = conf.create_accounts(&PlayOptions::default());
+    assert!(plays.iter().all(|play| play.name != "Prune groups."));
+
+    let plays = conf.create_accounts(&PlayOptions {
+        prune_groups: true,
+        ..Default::default()
+    });
+    let facts = plays
+        .iter()
+        .filter(|play| play.name.starts_with("Populate desired group facts"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        facts.len(),
+        conf.users
+            .iter()
+            .map(|user| user.access.len())
+            .sum::<usize>()
+    );
+    assert_eq!(facts[0].hosts, conf.users[0].access[0].hosts);
+
+    let prune = plays.last().unwrap();
+    assert_eq!(prune.name, "Prune groups.");
+    let stale = prune.tasks[2].module.params["stale_groups"]
+        .as_str()
+        .unwrap();
+    assert!(stale.contains("\"sudoerjoe\""));
+    assert!(stale.contains("\"sshman-sudoer\""));
+    assert_eq!(
+        prune.tasks[3].module.params.get("state"),
+        Some(&Value::from("absent"))
+    );
+
+    // Every desired group fact is set before the prune play reads them.
+    let coalesced = conf.create_accounts(&PlayOptions {
+        prune_groups: true,
+        coalesce: true,
+        ..Default::default()
+    });
+    assert_eq!(coalesced.last().unwrap().name, "Prune groups.");
+    assert_eq!(
+        coalesced
+            .iter()
+            .filter(|play| play.name == "Prune groups.")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_trust_cas() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(AnsiblePlay::trust_cas(&conf.users[0]).is_empty());
+
+    let ca = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIARL4yy4L4t/nzjMHyBjeJJeXjb4kncXYOtS+w9I4Wrj ca";
+    conf.users[0].access[0].trusted_cas = vec![ca.to_string()];
+    assert!(conf.validate_keys().is_ok());
+
+    let plays = AnsiblePlay::trust_cas(&conf.users[0]);
+    assert_eq!(plays.len(), 1);
+    assert_eq!(
+        plays[0].tasks[0].module.params.get("line"),
+        Some(&Value::from(ca))
+    );
+    let sshd = &plays[0].tasks[1];
+    assert_eq!(
+        sshd.params.get("notify"),
+        Some(&Value::from(plays[0].handlers[0].name))
+    );
+    assert_eq!(plays[0].handlers[0].module.name, "ansible.builtin.service");
+
+    conf.users[0].access[0].trusted_cas = vec!["ssh-ed25519 notakey".to_string()];
+    assert!(conf.validate_keys().is_err());
+}
+
+#[test]
+fn test_shell_and_home() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let param = |conf: &SSHConfig, name: &str| {
+        AnsiblePlay::create_user(&conf.users[0])[0]
+            .tasks
+            .last()
+            .unwrap()
+            .module
+            .params
+            .get(name)
+            .cloned()
+    };
+    assert_eq!(param(&conf, "shell"), None);
+    assert_eq!(param(&conf, "home"), None);
+
+    conf.users[0].access[0].shell = Some("/bin/zsh".to_string());
+    conf.users[0].access[0].home = Some("/srv/home/sudoerjoe".to_string());
+    assert_eq!(param(&conf, "shell"), Some(Value::from("/bin/zsh")));
+    assert_eq!(
+        param(&conf, "home"),
+        Some(Value::from("/srv/home/sudoerjoe"))
+    );
+
+    conf.users[0].access[0].role = Role::Disabled;
+    assert_eq!(
+        param(&conf, "shell"),
+        Some(Value::from("/usr/sbin/nologin"))
+    );
+}
+
+#[test]
+fn test_playbook_command() {
+    let opts = RunOptions {
+        check: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        playbook_command(
+            &opts.playbook_args(&["--limit".to_string(), "web".to_string()]),
+            Path::new("/tmp/playbook.yml")
+        ),
+        vec![
+            "ansible-playbook",
+            "--check",
+            "--diff",
+            "--limit",
+            "web",
+            "/tmp/playbook.yml"
+        ]
+    );
+
+    // ansible-pull has no --forks, so it is left out.
+    let opts = RunOptions {
+        forks: NonZeroU32::new(20),
+        pull: Some("https://git.example.com/inventory.git".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        pull_command(
+            opts.pull.as_deref().unwrap(),
+            &opts.playbook_args(&["-i".to_string(), "hosts.yml".to_string()]),
+            Path::new(PULL_PLAYBOOK)
+        ),
+        vec![
+            "ansible-pull",
+            "--url",
+            "https://git.example.com/inventory.git",
+            "-i",
+            "hosts.yml",
+            "/var/lib/ansible-sshman/playbook.yml"
+        ]
+    );
+}
+
+#[test]
+fn test_connection_settings() {
+    let conf: SSHConfig =
+        serde_yaml::from_str("connection:\n  user: deploy\n  port: 2222\nusers: []\n").unwrap();
+    let connection = Connection {
+        private_key_file: Some("~/.ssh/deploy".to_string()),
+        port: Some(22),
+        ..Default::default()
+    }
+    .or(conf.connection.clone().unwrap());
+    assert_eq!(connection.user.as_deref(), Some("deploy"));
+    assert_eq!(connection.port, Some(22));
+
+    let opts = RunOptions {
+        connection,
+        ..Default::default()
+    };
+    assert_eq!(
+        opts.playbook_args(&[]),
+        vec![
+            "-e",
+            r#"{"ansible_port":22,"ansible_ssh_private_key_file":"~/.ssh/deploy","ansible_user":"deploy"}"#
+        ]
+    );
+    assert!(RunOptions::default().playbook_args(&[]).is_empty());
+
+    let forks: SSHConfig = serde_yaml::from_str("forks: 20\nusers: []\n").unwrap();
+    let opts = RunOptions {
+        forks: forks.forks,
+        ..Default::default()
+    };
+    assert_eq!(opts.playbook_args(&[]), vec!["--forks", "20"]);
+
+    let opts = RunOptions {
+        ask_become_pass: true,
+        vault_password_file: Some("~/.vault_pass".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        opts.playbook_args(&["--tags".to_string(), "keys".to_string()]),
+        vec![
+            "--ask-become-pass",
+            "--vault-password-file",
+            "~/.vault_pass",
+            "--tags",
+            "keys"
+        ]
+    );
+
+    let opts = RunOptions {
+        extra_vars_file: Some("site.yml".to_string()),
+        extra_vars: vec![
+            "proxy=http://proxy:3128".parse().unwrap(),
+            "motd=managed by sshman".parse().unwrap(),
+            "proxy=".parse().unwrap(),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(
+        opts.playbook_args(&[]),
+        vec![
+            "-e",
+            "@site.yml",
+            "-e",
+            r#"{"motd":"managed by sshman","proxy":""}"#
+        ]
+    );
+    for invalid in ["proxy", "=value", "1st=value", "my-var=value"] {
+        assert!(invalid.parse::<ExtraVar>().is_err());
+    }
+
+    assert!(serde_yaml::from_str::<SSHConfig>("forks: 0\nusers: []\n").is_err());
+
+    let other: SSHConfig = serde_yaml::from_str("connection:\n  user: admin\nusers: []\n").unwrap();
+    assert!(conf.merge(other).is_err());
+}
+
+#[test]
+fn test_merge_configs() {
+    let read = || -> SSHConfig {
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap()
+    };
+
+    let mut first = read();
+    let mut second = read();
+    second.users = second.users.split_off(2);
+    first.users.truncate(2);
+    first.sudoers_defaults = vec!["timestamp_timeout=5".to_string()];
+    second.sudoers_defaults = vec!["timestamp_timeout=5".to_string(), "env_reset".to_string()];
+
+    let merged = first.merge(second).unwrap();
+    assert_eq!(
+        merged
+            .users
+            .iter()
+            .map(|user| &user.name)
+            .collect::<Vec<_>>(),
+        read()
+            .users
+            .iter()
+            .map(|user| &user.name)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        merged.sudoers_defaults,
+        vec!["timestamp_timeout=5", "env_reset"]
+    );
+
+    let err = merged.merge(read()).unwrap_err();
+    assert!(err.message.contains("sudoerjoe, nopasspetey"));
+}
+
+#[test]
+fn test_host_limit() {
+    let limit: HostLimit = "canary:!canary3".parse().unwrap();
+    assert_eq!(limit.restrict("all"), "all:&canary:!canary3");
+    assert_eq!(
+        limit.restrict("sydney:melbourne:&staging:!syd-stg"),
+        "sydney:melbourne:&staging:!syd-stg:&canary:!canary3"
+    );
+    assert!("web:db".parse::<HostLimit>().is_err());
+    assert!("web:!".parse::<HostLimit>().is_err());
+
+    // The restricted pattern matches the intersection when resolved.
+    let inventory = Inventory::read("test/inventory.yml").unwrap();
+    let limit: HostLimit = "staging:!syd-stg".parse().unwrap();
+    assert_eq!(
+        inventory
+            .get_pattern_hosts(&limit.restrict("melbourne:sydney"))
+            .unwrap(),
+        HashSet::from(["mel-stg".to_string()])
+    );
+
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let opts = PlayOptions {
+        limit: Some("canary".parse().unwrap()),
+        ..Default::default()
+    };
+    for plays in [conf.create_accounts(&opts), AnsiblePlay::diff(&conf, &opts)] {
+        assert!(plays
+            .iter()
+            .all(|play| play.hosts.matches(":&canary").count() == 1));
+    }
+}
+
+#[test]
+fn test_normalized_key_comparison() {
+    let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc";
+    let restricted = format!("command=\"uptime\",no-pty {key} joe@laptop");
+    assert_eq!(keys::normalize_key(&restricted).as_deref(), Some(key));
+    assert_eq!(keys::normalize_key("not a key"), None);
+
+    let output: serde_json::Value = serde_json::json!({
+        "plays": [{"tasks": [{
+            "task": {"name": KEY_FACTS_TASK},
+            "hosts": {"web1": {"msg": {
+                "desired": {"joe": [key]},
+                "actual": {"joe": [restricted, "unparseable"]}
+            }}}
+        }]}]
+    });
+    let diffs = report::parse_diff_output(&output);
+    assert_eq!(diffs[0].add, Vec::<String>::new());
+    assert_eq!(diffs[0].remove, vec!["unparseable"]);
+    assert_eq!(diffs[0].unchanged, vec![key]);
+}
+
+#[test]
+fn test_generate_ssh_key() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    conf.users[1].access[0].ssh_key_type = Some("ed25519".to_string());
+    assert_eq!(conf.warnings().len(), 1);
+
+    conf.users[1].access[0].generate_ssh_key = Some(true);
+    assert!(conf.warnings().is_empty());
+    let play = &AnsiblePlay::create_user(&conf.users[1])[0];
+    let params = &play.tasks.last().unwrap().module.params;
+    assert_eq!(params.get("generate_ssh_key"), Some(&Value::Bool(true)));
+    assert_eq!(params.get("ssh_key_type"), Some(&Value::from("ed25519")));
+    assert_eq!(params.get("ssh_key_bits"), None);
+
+    // Inbound keys are still authorized as usual.
+    assert!(!AnsiblePlay::authorize_keys(&conf.users[1]).is_empty());
+}
+
+#[test]
+fn test_user_comment() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let comment = |conf: &SSHConfig| {
+        AnsiblePlay::create_user(&conf.users[0])[0]
+            .tasks
+            .last()
+            .unwrap()
+            .module
+            .params
+            .get("comment")
+            .cloned()
+    };
+    assert_eq!(comment(&conf), None);
+
+    conf.users[0].comment = Some("Joe Bloggs, OPS-42".to_string());
+    assert_eq!(comment(&conf), Some(Value::from("Joe Bloggs, OPS-42")));
+}
+
+#[test]
+fn test_stable_uids() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let uid = |user: &SSHUser| {
+        AnsiblePlay::create_user(user)[0]
+            .tasks
+            .last()
+            .unwrap()
+            .module
+            .params
+            .get("uid")
+            .cloned()
+    };
+    assert_eq!(uid(&conf.users[0]), None);
+
+    conf.users[0].uid = Some(2001);
+    conf.users[2].uid = Some(2002);
+    assert!(conf.check(&CheckOptions::default()).is_ok());
+    assert_eq!(uid(&conf.users[0]), Some(Value::from(2001)));
+    assert_eq!(uid(&conf.users[2]), Some(Value::from("0")));
+
+    conf.users[1].uid = Some(2001);
+    let err = conf.check(&CheckOptions::default()).unwrap_err();
+    assert!(err
+        .message
+        .contains("users sudoerjoe, nopasspetey share uid 2001"));
+}
+
+#[test]
+fn test_task_notify() {
+    let task = AnsibleTask {
+        name: "Change config.",
+        module: AnsibleModule::debug("changed"),
+        params: HashMap::new(),
+    };
+    let task = task.notify("First.");
+    assert_eq!(task.params.get("notify"), Some(&Value::from("First.")));
+    let task = task.notify("Second.").notify("Third.");
+    assert_eq!(
+        task.params.get("notify"),
+        Some(&Value::from(vec!["First.", "Second.", "Third."]))
+    );
+}
+
+#[test]
+fn test_rollback() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let modules = |plays: &[AnsiblePlay]| {
+        plays
+            .iter()
+            .flat_map(|play| play.tasks.iter().map(|task| task.module.name))
+            .collect::<Vec<_>>()
+    };
+
+    let plays = conf.rollback_plays(false, &PlayOptions::default());
+    assert!(!modules(&plays).contains(&"ansible.builtin.user"));
+    let groups = plays.last().unwrap();
+    assert_eq!(groups.hosts, "all");
+    assert!(groups.tasks.iter().any(|task| {
+        task.module.params.get("path") == Some(&Value::from("/etc/sudoers.d/sshman-sudoer"))
+    }));
+
+    let purged = conf.rollback_plays(true, &PlayOptions::default());
+    let removed = purged
+        .iter()
+        .flat_map(|play| &play.tasks)
+        .filter(|task| task.module.name == "ansible.builtin.user")
+        .count();
+    let blocked = conf
+        .users
+        .iter()
+        .flat_map(|user| &user.access)
+        .filter(|stmt| stmt.role == Role::Blocked)
+        .count();
+    let stmts = conf.users.iter().flat_map(|user| &user.access).count();
+    assert_eq!(removed, stmts - blocked);
+}
+
+#[test]
+fn test_config_version() {
+    let list: SSHConfig = serde_yaml::from_str("[]").unwrap();
+    assert_eq!(list.version, 1);
+
+    let map: SSHConfig = serde_yaml::from_str("users: []").unwrap();
+    assert_eq!(map.version, CONFIG_VERSION);
+    let map: SSHConfig = serde_yaml::from_str("version: 1\nusers: []").unwrap();
+    assert_eq!(map.version, 1);
+
+    let err =
+        serde_yaml::from_str::<SSHConfig>(&format!("version: {}\nusers: []", CONFIG_VERSION + 1))
+            .unwrap_err();
+    assert!(err.to_string().contains("unsupported config version"));
+    assert!(serde_yaml::from_str::<SSHConfig>("version: 0\nusers: []").is_err());
+
+    let yaml = serde_yaml::to_string(&SSHConfig::default()).unwrap();
+    assert!(yaml.starts_with(&format!("version: {CONFIG_VERSION}\n")));
+}
+
+#[test]
+fn test_gather_facts() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    assert!(conf
+        .create_accounts(&PlayOptions::default())
+        .iter()
+        .all(|play| !play.gather_facts));
+
+    conf.users[0].access[0].gather_facts = true;
+    assert!(AnsiblePlay::create_user(&conf.users[0])[0].gather_facts);
+    assert!(AnsiblePlay::authorize_keys(&conf.users[0])[0].gather_facts);
+    assert!(!AnsiblePlay::create_user(&conf.users[1])[0].gather_facts);
+}
+
+#[test]
+fn test_run_with_retries() {
+    let opts = RunOptions {
+        timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (status, stdout) =
+        run_with_retries(Command::new("echo").arg("ok").stdout(Stdio::piped()), &opts).unwrap();
+    assert!(status.success());
+    assert_eq!(stdout, b"ok\n");
+
+    let (status, _) = run_with_retries(&mut Command::new("false"), &opts).unwrap();
+    assert!(!status.success());
+
+    let err = run_with_retries(Command::new("sleep").arg("5"), &opts).unwrap_err();
+    assert_eq!(err.attempts, 1);
+    assert!(matches!(err.failure, PlaybookFailure::Timeout(_)));
+
+    let opts = RunOptions { retries: 1, ..opts };
+    let err =
+        run_with_retries(&mut Command::new("/nonexistent/ansible-playbook"), &opts).unwrap_err();
+    assert_eq!(err.attempts, 2);
+    assert!(matches!(err.failure, PlaybookFailure::Spawn(_)));
+}
+
+#[test]
+fn test_test_commands() {
+    let opts = RunOptions {
+        limit: Some("web".to_string()),
+        ..Default::default()
+    };
+    let stages = test_commands(
+        "test/inventory.yml",
+        &["-v".to_string()],
+        Path::new("/tmp/playbook.yml"),
+        &opts,
+    );
+    assert_eq!(stages[0].0, "syntax check");
+    assert_eq!(
+        stages[0].1,
+        [
+            "ansible-playbook",
+            "--limit",
+            "web",
+            "-i",
+            "test/inventory.yml",
+            "--syntax-check",
+            "-v",
+            "/tmp/playbook.yml"
+        ]
+    );
+    assert_eq!(stages[1].0, "check mode run");
+    assert!(stages[1].1.contains(&"--check".to_string()));
+}
+
+#[test]
+fn test_progress_lines() {
+    let mut progress = Progress::default();
+    let lines = [
+        "",
+        "PLAY [Create accounts for alice.] ************************************",
+        "TASK [Create group user group.] **************************************",
+        "changed: [web-1]",
+        "TASK [Create sudoer account.] ****************************************",
+        "ok: [web-1]",
+        "RUNNING HANDLER [Reload sshd.] ***************************************",
+    ]
+    .iter()
+    .filter_map(|line| progress.format(line))
+    .collect_vec();
+    assert_eq!(
+        lines,
+        [
+            "sshman [task 0] PLAY [Create accounts for alice.]",
+            "sshman [task 1] TASK [Create group user group.]",
+            "sshman [task 1] changed: [web-1]",
+            "sshman [task 2] TASK [Create sudoer account.]",
+            "sshman [task 2] ok: [web-1]",
+            "sshman [task 3] RUNNING HANDLER [Reload sshd.]",
+        ]
+    );
+
+    let opts = RunOptions {
+        progress: true,
+        ..Default::default()
+    };
+    let status = run_passthrough(
+        Command::new("printf").arg("TASK [Create caf\\351 account.] ***\\n\\377\\n"),
+        &opts,
+    )
+    .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_read_inventory() {
+    let timeout = Some(Duration::from_millis(200));
+    let inventory =
+        read_inventory(Command::new("cat").arg("test/inventory_flat.yml"), timeout).unwrap();
+    assert!(!inventory.all_hosts().is_empty());
+
+    let err = read_inventory(Command::new("sleep").arg("5"), timeout).unwrap_err();
+    assert!(err.downcast_ref::<InventoryTimeoutError>().is_some());
+
+    let err = read_inventory(&mut Command::new("false"), timeout).unwrap_err();
+    assert!(err.downcast_ref::<InvOutputParseError>().is_some());
+
+    let err = read_inventory(
+        Command::new("sh").args([
+            "-c",
+            "echo '[WARNING]: no inventory'; head -c 1000 /dev/zero",
+        ]),
+        timeout,
+    )
+    .unwrap_err();
+    let err = err.downcast_ref::<InvOutputParseError>().unwrap();
+    assert!(err.raw.starts_with("[WARNING]: no inventory"));
+    assert!(err.raw.ends_with("..."));
+    assert!(err.raw.len() <= 303);
+
+    let err = InvOutputParseError::new(String::new(), b"bad \xff byte");
+    assert_eq!(err.raw, "bad  byte");
+}
+
+#[test]
+fn test_inventory_warnings() {
+    let timeout = Some(Duration::from_millis(500));
+    let inventory = read_inventory(
+        Command::new("sh").args([
+            "-c",
+            "echo '[DEPRECATION WARNING]: Some option is deprecated. This feature will be removed'; \
+            echo 'in version 2.19. Deprecation warnings can be disabled.'; \
+            printf 'all:\n  hosts:\n    caf\\351:\n'",
+        ]),
+        timeout,
+    )
+    .unwrap();
+    assert_eq!(
+        inventory.all_hosts(),
+        HashSet::from(["caf\u{fffd}".to_string()])
+    );
+
+    let yaml = "all:\n  hosts: {}\n";
+    assert_eq!(skip_warnings(yaml), yaml);
+    assert_eq!(
+        skip_warnings(&format!("[WARNING]: empty\n---\n{yaml}")),
+        format!("---\n{yaml}")
+    );
+    assert_eq!(
+        skip_warnings("[WARNING]: no inventory"),
+        "[WARNING]: no inventory"
+    );
+}
+
+#[test]
+fn test_summary() {
+    let mut conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let summary = conf.summary();
+    assert_eq!(summary.users, conf.users.len());
+    assert_eq!(
+        summary.keys,
+        conf.users
+            .iter()
+            .filter(|user| user.access[0].role != Role::Blocked)
+            .map(|user| user.present_keys().count())
+            .sum::<usize>()
+    );
+
+    // Blocked users have no keys authorized, and repeated patterns are counted once.
+    conf.users[0].access[0].role = Role::Blocked;
+    conf.users[1].access[0].hosts = conf.users[2].access[0].hosts.clone();
+    let blocked = conf.summary();
+    assert_eq!(
+        blocked.keys,
+        summary.keys - conf.users[0].present_keys().count()
+    );
+    assert_eq!(blocked.host_patterns, summary.host_patterns - 1);
+    assert!(blocked
+        .to_string()
+        .starts_with(&format!("Processed {} users", conf.users.len())));
+}
+
+#[test]
+fn test_keys_report() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+
+    assert_eq!(
+        conf.keys_report(Some("nopasspetey")).unwrap(),
+        format!(
+            "# nopasspetey\n# melbourne as passwordless sudo user\n{}\n\n",
+            conf.users[1].authorized_keys(&conf.users[1].access[0])
+        )
+    );
+    assert_eq!(
+        conf.keys_report(Some("igotfired")).unwrap(),
+        "# igotfired\n# * as blocked user, keys removed\n\n"
+    );
+    assert_eq!(
+        conf.keys_report(None).unwrap().matches("\n# ").count() + 1,
+        conf.users.len() + conf.users.iter().map(|u| u.access.len()).sum::<usize>()
+    );
+    assert!(conf.keys_report(Some("nobody")).is_err());
+}
+
+#[test]
+fn test_import() {
+    let output = serde_json::json!({"plays": [{"tasks": [{
+        "task": {"name": IMPORT_KEYS_TASK},
+        "hosts": {
+            "mel-stg": {"msg": {
+                "alice": ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc alice@laptop", ""],
+                "root": ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIARL4yy4L4t/nzjMHyBjeJJeXjb4kncXYOtS+w9I4Wrj admin"],
+                "nobody": []
+            }},
+            "syd-stg": {"msg": {
+                "alice": ["no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc alice@desktop"]
+            }}
+        }
+    }]}]});
+
+    let found = report::parse_import_output(&output);
+    assert_eq!(found.len(), 3);
+
+    let conf = SSHConfig::from_found_keys(&found, "nopass".parse().unwrap());
+    assert_eq!(conf.users.len(), 1);
+    let alice = &conf.users[0];
+    assert_eq!(alice.name, "alice");
+    assert_eq!(alice.pubkeys.len(), 1);
+    assert_eq!(alice.access[0].hosts, "mel-stg:syd-stg");
+    assert_eq!(alice.access[0].role, Role::Nopass);
+
+    let yaml = serde_yaml::to_string(&conf).unwrap();
+    assert!(!yaml.contains("null"));
+    let parsed: SSHConfig = serde_yaml::from_str(&yaml).unwrap();
+    assert_eq!(parsed.users, conf.users);
+    assert!(parsed.validate_keys().is_ok());
+    assert!("admin".parse::<Role>().is_err());
+}
+
+#[test]
+fn test_sshd_config_task() {
+    let task = AnsibleTask {
+        name: "Disable password logins.",
+        module: AnsibleModule::sshd_config(
+            "^#?\\s*PasswordAuthentication\\s",
+            Some("PasswordAuthentication no"),
+        ),
+        params: HashMap::new(),
+    }
+    .notify("Reload sshd.");
+    let yaml: Value = serde_yaml::to_value(&task).unwrap();
+    assert_eq!(
+        yaml,
+        serde_yaml::from_str::<Value>(
+            r#"
+name: Disable password logins.
+ansible.builtin.lineinfile:
+  path: /etc/ssh/sshd_config
+  regexp: '^#?\s*PasswordAuthentication\s'
+  line: PasswordAuthentication no
+  validate: sshd -t -f %s
+notify: Reload sshd.
+"#
+        )
+        .unwrap()
+    );
+
+    let removal = AnsibleModule::sshd_config("^\\s*Banner\\s", None);
+    assert_eq!(removal.params.get("state"), Some(&Value::from("absent")));
+    assert!(!removal.params.contains_key("line"));
+}
+
+#[test]
+fn test_pubkey_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let key =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc joe";
+    fs::create_dir(dir.path().join("keys")).unwrap();
+    fs::write(
+        dir.path().join("keys/joe.pub"),
+        format!("# joe's laptop\n{key}\n\n"),
+    )
+    .unwrap();
+    let config = dir.path().join("config.yml");
+    fs::write(
+        &config,
+        "- name: joe\n  pubkey_files: [keys/joe.pub]\n  access:\n    - hosts: all\n      role: sudoer\n",
+    )
+    .unwrap();
+
+    let conf = read_config(config.to_str().unwrap()).unwrap();
+    assert_eq!(conf.users[0].present_keys().collect::<Vec<_>>(), vec![key]);
+
+    fs::remove_file(dir.path().join("keys/joe.pub")).unwrap();
+    let err = read_config(config.to_str().unwrap()).unwrap_err();
+    assert!(err.message.contains("failed to read pubkey file"));
+    assert!(err.message.contains("keys/joe.pub for user joe"));
+}
+
+#[test]
+fn test_key_groups_across_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let key =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc joe";
+    let groups = dir.path().join("groups.yml");
+    fs::write(
+        &groups,
+        format!("version: 1\nkey_groups:\n  ops:\n    - {key}\nusers: []\n"),
+    )
+    .unwrap();
+    let users = dir.path().join("users.yml");
+    fs::write(
+        &users,
+        "version: 1\nusers:\n  - name: joe\n    pubkeys: ['@ops']\n    access:\n      - hosts: all\n        role: sudoer\n",
+    )
+    .unwrap();
+    let paths = [groups, users].map(|path| path.to_str().unwrap().to_string());
+
+    let conf = read_configs(&paths).unwrap();
+    assert_eq!(conf.users[0].present_keys().collect::<Vec<_>>(), vec![key]);
+
+    let err = read_configs(&paths[1..]).unwrap_err();
+    assert!(
+        err.message.contains("undefined key group ops"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn test_config_includes() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("users")).unwrap();
+    fs::write(
+        dir.path().join("users/joe.yml"),
+        "name: joe\npubkeys: !include ../keys.yml\naccess:\n  - hosts: all\n    role: sudoer\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("keys.yml"),
+        "- ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc joe\n",
+    )
+    .unwrap();
+    let config = dir.path().join("config.yml");
+    fs::write(&config, "users:\n  - !include users/joe.yml\n").unwrap();
+
+    let conf = read_config(config.to_str().unwrap()).unwrap();
+    assert_eq!(conf.users[0].name, "joe");
+    assert_eq!(conf.users[0].present_keys().count(), 1);
+
+    // An included file that includes the config is a cycle.
+    fs::write(dir.path().join("keys.yml"), "!include config.yml\n").unwrap();
+    let err = read_config(config.to_str().unwrap()).unwrap_err();
+    assert!(err.message.contains("include cycle"), "{}", err.message);
+    assert!(err.message.ends_with("config.yml"));
+}
+
+#[test]
+fn test_inventory_shapes() {
+    let flat = Inventory::read("test/inventory_flat.yml").unwrap();
+    let hosts = |inventory: &Inventory, pattern: &str| {
+        inventory
+            .list_hosts(pattern)
+            .unwrap()
+            .into_keys()
+            .sorted()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(hosts(&flat, "all"), vec!["db1", "web1", "web2"]);
+    assert_eq!(
+        flat.list_hosts("web1").unwrap()["web1"],
+        "10.0.3.1".to_string()
+    );
+
+    let nested = Inventory::read("test/inventory_nested.yml").unwrap();
+    assert_eq!(
+        hosts(&nested, "australia"),
+        vec!["mel-web1", "syd-db1", "syd-web1", "syd-web2"]
+    );
+    assert_eq!(
+        hosts(&nested, "sydney:!syd-db"),
+        vec!["syd-web1", "syd-web2"]
+    );
+    assert_eq!(hosts(&nested, "all").len(), 5);
+}
+
+#[test]
+fn test_coalesce() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let plays = conf.create_accounts(&PlayOptions::default());
+    let coalesced = conf.create_accounts(&PlayOptions {
+        coalesce: true,
+        ..Default::default()
+    });
+
+    let patterns = plays
+        .iter()
+        .map(|play| &play.hosts)
+        .unique()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        coalesced.iter().map(|play| &play.hosts).collect::<Vec<_>>(),
+        patterns
+    );
+    assert!(coalesced.len() < plays.len());
+
+    // Each pattern gets the same tasks in the same order.
+    let task_names = |plays: &[AnsiblePlay], hosts: &str| {
+        plays
+            .iter()
+            .filter(|play| play.hosts == hosts)
+            .flat_map(|play| play.tasks.iter().map(|task| task.name))
+            .collect::<Vec<_>>()
+    };
+    for hosts in patterns {
+        assert_eq!(task_names(&plays, hosts), task_names(&coalesced, hosts));
+    }
+    let superuser = coalesced.iter().find(|play| play.hosts == "*").unwrap();
+    assert_eq!(superuser.name, "Manage access on *.");
+}
+
+#[test]
+fn test_key_options_and_comments() {
+    let mut conf: SSHConfig = serde_yaml::from_str(
+        r#"
+- name: joe
+  pubkeys:
+    - ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc joe@desktop
+    - key: ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIARL4yy4L4t/nzjMHyBjeJJeXjb4kncXYOtS+w9I4Wrj joe@ci
+      options: from="10.0.0.0/8",no-pty
+      comment: ci runner
+  access:
+    - hosts: all
+      role: sudoer
+"#,
+    )
+    .unwrap();
+    assert!(conf.validate_keys().is_ok());
+
+    let plays = AnsiblePlay::authorize_keys(&conf.users[0]);
+    let exclusive = plays[0]
+        .tasks
+        .iter()
+        .filter(|task| task.module.params.get("exclusive") == Some(&Value::from("true")))
+        .collect::<Vec<_>>();
+    assert_eq!(exclusive.len(), 1);
+    assert_eq!(
+        exclusive[0].module.params.get("key"),
+        Some(&Value::from(
+            "from=\"10.0.0.0/8\",no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIARL4yy4L4t/nzjMHyBjeJJeXjb4kncXYOtS+w9I4Wrj ci runner\n\
+            ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILaoRJBFVuJpx4fZ6Gh4WAkiPT2MoMfUJlog6byttAKc joe@desktop"
+        ))
+    );
+
+    let yaml = serde_yaml::to_string(&conf.users[0].pubkeys).unwrap();
+    let parsed: Vec<PubKey> = serde_yaml::from_str(&yaml).unwrap();
+    assert_eq!(parsed, conf.users[0].pubkeys);
+
+    conf.users[0].pubkeys[1].options = Some("from=\"unclosed".to_string());
+    assert!(conf.validate_keys().is_err());
+}
+
+#[test]
+fn test_split_playbook() {
+    let conf: SSHConfig =
+        serde_yaml::from_str(&fs::read_to_string("test/config.yml").unwrap()).unwrap();
+    let plays = conf.create_accounts(&PlayOptions::default());
+    let dir = tempfile::tempdir().unwrap();
+
+    let index =
+        write_split_playbook(&plays, &dir.path().join("playbook"), &RunOptions::default()).unwrap();
+    let imports: Vec<HashMap<String, String>> =
+        serde_yaml::from_str(&fs::read_to_string(&index).unwrap()).unwrap();
+    assert_eq!(imports.len(), plays.len());
+    assert_eq!(imports[0]["import_playbook"], "01-create-groups.yml");
+
+    // Concatenating the files in order gives the whole playbook.
+    let combined = imports
+        .iter()
+        .flat_map(|import| {
+            let path = index.parent().unwrap().join(&import["import_playbook"]);
+            serde_yaml::from_str::<Vec<Value>>(&fs::read_to_string(path).unwrap()).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        Value::Sequence(combined),
+        serde_yaml::to_value(&plays).unwrap()
+    );
+}